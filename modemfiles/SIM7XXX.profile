@@ -0,0 +1,28 @@
+# SIMCom SIM7000/SIM7500/SIM7600 series in transparent TCP mode.
+baud = 115200
+data_bits = 8
+parity = none
+stop_bits = 1
+flow_control = none
+apn = internet.telekom
+escape = +++
+escape_guard = 1000
+closed = CLOSED
+closed = +IPCLOSE
+closed = NO CARRIER
+
+[init]
+AT
+WAIT 500
+AT+CIPMODE=1
+AT+CGDCONT=1,IP,"$APN"
+AT+NETOPEN
+WAIT 2000
+
+[hangup]
+AT+CIPCLOSE=0
+
+[connect]
+AT
+AT+IPADDR
+AT+CIPOPEN=0,"TCP","$IP",$PORT
@@ -0,0 +1,30 @@
+# SIMCom SIM800 series in transparent TCP mode.
+baud = 115200
+data_bits = 8
+parity = none
+stop_bits = 1
+flow_control = none
+apn = internet.telekom
+escape = +++
+escape_guard = 1000
+closed = CLOSED
+closed = NO CARRIER
+
+[init]
+AT
+WAIT 500
+AT+CFUN?
+AT+CPIN?
+AT+CIPMODE=1
+WAIT 500
+AT+CSTT="$APN","",""
+AT+CIICR
+WAIT 2000
+
+[hangup]
+AT+CIPCLOSE=0
+
+[connect]
+AT
+AT+CIFSR
+AT+CIPSTART=TCP,"$IP",$PORT
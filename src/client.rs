@@ -1,78 +1,190 @@
 use core::panic;
 use std::{
     collections::HashMap,
-    fs,
-    io::{Read, Write},
-    net::TcpStream,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Sender},
     thread,
     time::{Duration, SystemTime},
     vec,
 };
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
 use serial::SerialPort;
 
-use crate::{Connection, PacketType, SocketAdapter};
+#[cfg(unix)]
+use crate::Pipe;
+use crate::{
+    check_name, read_addresses, read_name, read_target, round_trip, target_allowed, wait_readable,
+    Addresses, ArqTimers, Connection, Framed, Listener, Modem, ModemProfile, Packet, PacketType,
+    PortStatus, ProxyProtocol, Received, RefuseReason, SocketAdapter, StreamInfo, TokenBucket,
+    CHECKSUMS, HEADER, LOCAL_STREAM, RELIABLE,
+};
+
+/// The most streams being connected to their destinations at once. Each has a thread, so further
+/// ones wait until some are done.
+const MAX_CONNECTING: usize = 64;
+
+/// The most data kept for a stream that isn't connected to its destination yet. Streams that are
+/// sent more are closed.
+const MAX_PENDING_DATA: usize = 1024 * 1024;
+
+/// The longest delay between two attempts to connect a stream.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A port on the client whose connections are carried to `host`:`host_port` as seen from the
+/// server, like ssh -L.
+pub struct LocalForward<'a> {
+    pub port: u16,
+    pub host: &'a str,
+    pub host_port: u16,
+}
+
+/// A destination the server can route connections to by name, e.g. for a virtual host.
+pub struct Service<'a> {
+    pub name: &'a str,
+    /// An ip or hostname, or unix:<path> to connect to a unix socket.
+    pub dest_ip: &'a str,
+    pub dest_port: u16,
+    /// Forward connections to this service with as little delay as possible, see
+    /// `ClientParams::low_latency`.
+    pub low_latency: bool,
+}
 
 pub struct ClientParams<'a> {
     pub server_ip: &'a str,
     pub server_port: u16,
+    /// An ip or hostname, or unix:<path> to connect to a unix socket.
     pub dest_ip: &'a str,
     pub dest_port: u16,
     pub key: &'a str,
     pub sleep_delay_ms: u64,
     pub modem_port: Option<&'a str>,
     pub modem_baud: Option<u32>,
-    pub modem_init: Option<&'a str>,
+    pub modem_profile: Option<&'a str>,
     pub rate_limit_sleep: u64,
+    /// Seconds between modem status queries, 0 to disable them.
+    pub modem_status_interval: u64,
+    /// A second AT port of the modem to query status on, so transparent mode isn't interrupted.
+    pub modem_status_port: Option<&'a str>,
+    /// A command to talk to the server through instead of connecting to it, like ssh's
+    /// ProxyCommand. %h and %p are replaced with the server ip and port.
+    pub proxy_command: Option<&'a str>,
+    /// Bytes per second sent to the server in total, 0 for no limit.
+    pub upload_limit: u64,
+    /// Bytes per second sent to the server for each connection, 0 for no limit.
+    pub upload_limit_per_stream: u64,
+    /// Send a PROXY protocol header with the public peer's address to the destination.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Called for every new connection before it is forwarded to the destination.
+    pub on_new_stream: Option<&'a dyn Fn(&StreamInfo)>,
+    /// How often connecting to the destination is retried before the stream is refused.
+    pub connect_retries: u32,
+    /// Delay before the first retry, doubled for every further one up to a minute.
+    pub connect_retry_delay_ms: u64,
+    /// Ask the server to open this public port for us, 0 for any port it allows.
+    pub request_port: Option<u16>,
+    /// Local ports to forward to the server's side. It has to allow the destinations.
+    pub local_forwards: Vec<LocalForward<'a>>,
+    /// Destinations SOCKS5 peers of the server may connect to, as host:port. Either may be `*`.
+    pub allow_connect: Vec<&'a str>,
+    /// Destinations the server can route connections to instead of `dest_ip`.
+    pub services: Vec<Service<'a>>,
+    /// Forward connections to the destination with as little delay as possible, for interactive
+    /// protocols like ssh or games: TCP_NODELAY is set, every write is sent right away and these
+    /// connections are read first, so they get their share of the upload limit. Their frames
+    /// still queue behind those already sent to the tunnel.
+    pub low_latency: bool,
+    /// Check every frame with a CRC32, so line noise can't corrupt the streams. Always on for
+    /// modems.
+    pub checksums: bool,
+    /// Acknowledge frames and send lost ones again, so line noise on a modem or serial link
+    /// doesn't reset the streams.
+    pub reliable: bool,
+    /// How often the server is pinged.
+    pub ping_interval_ms: u64,
+    /// Give up on the server if it wasn't heard from for this long.
+    pub peer_timeout_ms: u64,
+    /// How long a read or write on the connection to the server may block, e.g. for the rest of
+    /// a frame.
+    pub io_timeout_ms: u64,
+    /// How long a read or write on a forwarded connection may block, and how long connecting it
+    /// to the destination may take.
+    pub stream_timeout_ms: u64,
+    /// On reliable tunnels: how long a received frame may wait for its acknowledgement, how
+    /// often missing frames are asked for, and how long the oldest unacknowledged frame waits
+    /// before it is sent again, until a round-trip time was measured.
+    pub ack_delay_ms: u64,
+    pub nack_interval_ms: u64,
+    pub retransmit_timeout_ms: u64,
+}
+
+/// A new stream that isn't connected to the destination yet.
+struct PendingStream {
+    addresses: Option<Addresses>,
+    /// Where a SOCKS5 peer wants to connect to, instead of the destination.
+    target: Option<(String, u16)>,
+    /// The index of the service to connect to instead of the destination.
+    service: Option<usize>,
+    attempts: u32,
+    next_attempt: SystemTime,
+    /// Whether a thread is connecting it right now.
+    connecting: bool,
+    /// Data from the server that arrived in the meantime.
+    data: Vec<u8>,
+    /// Whether the server's side finished sending in the meantime.
+    write_done: bool,
+}
+
+fn open_modem(params: &ClientParams, port: &str) -> (serial::SystemPort, ModemProfile) {
+    let profile = params
+        .modem_profile
+        .map(|x| ModemProfile::load(x).expect("invalid modem profile"))
+        .unwrap_or_default();
+    let mut settings = profile.settings;
+    if let Some(baud) = params.modem_baud {
+        settings.baud_rate = serial::BaudRate::from_speed(baud as usize);
+    }
+    let mut serial = serial::open(port).unwrap();
+    serial.configure(&settings).unwrap();
+    (serial, profile)
+}
+
+#[cfg(unix)]
+fn proxy_command(command: &str) -> Connection {
+    Connection::new_pipe(Pipe::spawn(command).unwrap(), true)
+}
+
+#[cfg(not(unix))]
+fn proxy_command(_command: &str) -> Connection {
+    panic!("proxy commands are only supported on unix.");
 }
 
 fn connect(params: &ClientParams) -> Connection {
     if let Some(modem_port) = params.modem_port {
-        let mut serial = serial::open(modem_port).unwrap();
-        serial
-            .configure(&serial::PortSettings {
-                baud_rate: serial::BaudRate::from_speed(
-                    params.modem_baud.unwrap_or(115200) as usize
-                ),
-                char_size: serial::CharSize::Bits8,
-                parity: serial::Parity::ParityNone,
-                stop_bits: serial::StopBits::Stop1,
-                flow_control: serial::FlowControl::FlowNone,
-            })
-            .unwrap();
-        if let Some(modem_init) = params.modem_init {
-            serial.set_timeout(Duration::from_millis(200)).unwrap();
-            for line in fs::read_to_string(modem_init)
-                .expect("invalid modem init file")
-                .lines()
-            {
-                let line = line
-                    .replace("$IP", &params.server_ip.to_string())
-                    .replace("$PORT", &params.server_port.to_string());
-                println!("> {line}");
-                serial.write_all((line + "\r\n").as_bytes()).unwrap();
-                let mut s = Vec::new();
-                let _ = serial.read_to_end(&mut s).is_ok();
-                if !s.is_empty() {
-                    println!(
-                        "< {}",
-                        String::from_utf8(s).unwrap().replace("\n", "\n< ").trim()
-                    );
-                }
-                thread::sleep(Duration::from_millis(300));
-            }
-            serial.set_timeout(Duration::from_millis(5000)).unwrap();
-            let mut s = Vec::new();
-            let _ = serial.read_to_end(&mut s).is_ok();
-            if !s.is_empty() {
-                println!(
-                    "< {}",
-                    String::from_utf8(s).unwrap().replace("\n", "\n< ").trim()
-                );
-            }
+        let (mut serial, profile) = open_modem(params, modem_port);
+        // without a second port, the status is queried after escaping from transparent mode.
+        if params.modem_status_interval != 0
+            && params.modem_status_port.is_none()
+            && profile.escape.is_empty()
+        {
+            panic!("the modem profile has no escape sequence, so its status needs a status port.");
         }
-        serial.set_timeout(Duration::from_millis(20000)).unwrap();
-        return Connection::new_serial(serial, true);
+        if params.modem_profile.is_some() {
+            profile
+                .dial(&mut serial, params.server_ip, params.server_port)
+                .unwrap();
+        }
+        return Connection::new_modem(Modem::new(serial, profile, true), true);
+    }
+    if let Some(command) = params.proxy_command {
+        return proxy_command(
+            &command
+                .replace("%h", params.server_ip)
+                .replace("%p", &params.server_port.to_string()),
+        );
     }
     Connection::new_tcp(
         TcpStream::connect((params.server_ip, params.server_port)).unwrap(),
@@ -80,90 +192,370 @@ fn connect(params: &ClientParams) -> Connection {
     )
 }
 
-fn resync(tcp: &mut SocketAdapter, id: &mut u64) {
-    let mut buf8 = [0u8; 8];
+fn is_low_latency(params: &ClientParams, stream: &PendingStream) -> bool {
+    match (&stream.target, stream.service) {
+        (Some(_), _) => false,
+        (None, Some(i)) => params.services[i].low_latency,
+        (None, None) => params.low_latency,
+    }
+}
+
+/// Where a pending stream is connected to.
+enum Destination {
+    Tcp(String, u16),
+    #[cfg(unix)]
+    Unix(String),
+}
+
+/// A connection to a destination, made on its own thread as it can take long.
+enum ConnectedStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A pending stream and the outcome of connecting it.
+type Connected = (u64, io::Result<ConnectedStream>);
+
+fn destination(params: &ClientParams, stream: &PendingStream) -> Destination {
+    let (ip, port) = match (&stream.target, stream.service) {
+        // SOCKS5 peers only ever get tcp connections.
+        (Some((host, port)), _) => return Destination::Tcp(host.clone(), *port),
+        (None, Some(i)) => (params.services[i].dest_ip, params.services[i].dest_port),
+        (None, None) => (params.dest_ip, params.dest_port),
+    };
+    #[cfg(unix)]
+    if let Some(path) = ip.strip_prefix("unix:") {
+        return Destination::Unix(path.to_owned());
+    }
+    Destination::Tcp(ip.to_owned(), port)
+}
+
+impl Destination {
+    fn connect(self, timeout: Duration) -> io::Result<ConnectedStream> {
+        match self {
+            Destination::Tcp(host, port) => {
+                let mut last_error = io::Error::new(ErrorKind::NotFound, "no address found");
+                for address in (host.as_str(), port).to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&address, timeout) {
+                        Ok(tcp) => return Ok(ConnectedStream::Tcp(tcp)),
+                        Err(e) => last_error = e,
+                    }
+                }
+                Err(last_error)
+            }
+            #[cfg(unix)]
+            Destination::Unix(path) => Ok(ConnectedStream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl From<ConnectedStream> for Connection {
+    fn from(value: ConnectedStream) -> Self {
+        match value {
+            ConnectedStream::Tcp(tcp) => Connection::new_tcp(tcp, false),
+            #[cfg(unix)]
+            ConnectedStream::Unix(unix) => Connection::new_unix(unix, false),
+        }
+    }
+}
+
+/// Aborts all streams after the two ends lost sync, as any of them may have lost data.
+fn drop_streams(
+    sockets: &mut HashMap<u64, SocketAdapter>,
+    pending: &mut HashMap<u64, PendingStream>,
+    opening: &mut HashMap<u64, Connection>,
+) {
+    for (_, x) in sockets.drain() {
+        let _ = x.internal.abort();
+    }
+    pending.clear();
+    for (_, x) in opening.drain() {
+        let _ = x.abort();
+    }
+}
+
+/// Drops all streams after frames from the server were lost, and has the server do the same.
+/// The tunnel itself stays up.
+fn reset(
+    tcp: &mut Framed,
+    reason: &str,
+    sockets: &mut HashMap<u64, SocketAdapter>,
+    pending: &mut HashMap<u64, PendingStream>,
+    opening: &mut HashMap<u64, Connection>,
+) {
+    println!();
+    eprintln!("Lost sync with the server ({reason}), resetting connections.");
+    drop_streams(sockets, pending, opening);
+    tcp.send(Packet::new(PacketType::Reset)).unwrap();
+}
+
+fn update_modem_status(
+    tcp: &mut SocketAdapter,
+    status_modem: Option<&mut Modem<serial::SystemPort>>,
+) {
+    let status = match status_modem {
+        Some(modem) => modem.status(),
+        None => {
+            // nothing may be left in our buffer while the modem is in command mode.
+            tcp.write_now().unwrap();
+            let Some(status) = tcp.internal.modem_status() else {
+                return;
+            };
+            status
+        }
+    };
     println!();
-    eprintln!("Server version mismatch or broken connection. Re-syncing in case of the latter...");
-    tcp.internal.set_print(false);
-    tcp.write_now().unwrap();
-    tcp.write(&[PacketType::Resync.ordinal() as u8]).unwrap();
-    tcp.write(&id.to_be_bytes()).unwrap();
-    tcp.write_now().unwrap();
-    eprintln!(
-        "Sent resync packet. Server should now wait 8 seconds and then send a resync-echo packet."
-    );
-    let mut buf = [0; 4096];
-    // read all packets that are still pending.
-    while let Some(Some(_x @ 1..)) = tcp.poll(&mut buf).ok() {}
-    // wait 5 seconds
-    thread::sleep(Duration::from_secs(5));
-    // read all packets that are still pending.
-    while let Some(Some(_x @ 1..)) = tcp.poll(&mut buf).ok() {}
-    // server should now have stopped sending packets.
-    let mut buf = [0];
-    eprintln!("Trying to receive the resync echo...");
-    tcp.read_now(&mut buf).unwrap();
-    if buf[0] as i8 == PacketType::ResyncEcho.ordinal() {
-        tcp.read_now(&mut buf8).unwrap();
-        *id = u64::from_be_bytes(buf8);
-        eprintln!("Successfully resynced. RevPFW3 can continue.");
-    } else {
-        eprintln!("Resync was not successful. Stopping.");
-        panic!("broken connection or server version mismatch.");
+    match status {
+        Ok(status) => {
+            println!("Modem: {status}");
+            tcp.internal.set_status_note(Some(format!("[{status}]")));
+        }
+        Err(e) => {
+            eprintln!("Unable to query modem status: {e}");
+            tcp.internal.set_status_note(None);
+        }
     }
-    tcp.internal.set_print(true);
 }
 
 pub fn client(params: ClientParams) {
     let mut buf1 = [0u8; 1];
+    let mut buf2 = [0u8; 2];
     let mut buf4 = [0u8; 4];
     let mut buf8 = [0u8; 8];
     let mut buf16 = [0u8; 16];
     let mut buf = [0; 1024];
+    // pongs would come too late to keep the server from being given up on.
+    if params.ping_interval_ms >= params.peer_timeout_ms {
+        panic!("the ping interval must be shorter than the peer timeout.");
+    }
+    for forward in &params.local_forwards {
+        check_name(forward.host);
+    }
+    for service in &params.services {
+        check_name(service.name);
+    }
+    let stream_timeout = Duration::from_millis(params.stream_timeout_ms);
     let mut tcp = connect(&params);
+    tcp.set_timeout(Duration::from_millis(params.io_timeout_ms))
+        .unwrap();
     tcp.set_print(false);
     println!("Syncing...");
-    tcp.write_all(&[b'R', b'P', b'F', 30]).unwrap();
+    tcp.write_all(&HEADER).unwrap();
     println!("Authenticating...");
     tcp.write_all(&(params.key.len() as u32).to_be_bytes())
         .unwrap();
     tcp.write_all(params.key.as_bytes()).unwrap();
+    // line noise is likely on modems, so frames are always checked there.
+    let checksums = params.checksums || params.modem_port.is_some();
+    let mut flags = if checksums { CHECKSUMS } else { 0 };
+    if params.reliable {
+        flags |= RELIABLE;
+    }
+    tcp.write_all(&flags.to_be_bytes()).unwrap();
 
     println!("Syncing...");
     tcp.read_exact(&mut buf4).unwrap();
-    if buf4 != [b'R', b'P', b'F', 30] {
-        panic!("RPF30 header expected, but not found. Make sure the server is actually running revpfw3!");
+    if buf4 != HEADER {
+        panic!(
+            "RPF{} header expected, but not found. Make sure the server is actually running the same revpfw3!",
+            HEADER[3]
+        );
+    }
+    tcp.read_exact(&mut buf4).unwrap();
+    let flags = u32::from_be_bytes(buf4);
+    if flags & RELIABLE != 0 {
+        println!("Sending lost frames again.");
+    } else if flags & CHECKSUMS != 0 {
+        println!("Checking frames with CRC32.");
     }
-    tcp.write_all(&[PacketType::KeepAlive.ordinal() as u8])
-        .unwrap();
     tcp.set_print(true);
 
     println!("READY!");
 
-    let mut tcp = SocketAdapter::new(tcp);
+    if params.low_latency || params.services.iter().any(|x| x.low_latency) {
+        tcp.set_nodelay(true).unwrap();
+    }
+    let timers = ArqTimers {
+        ack_delay: Duration::from_millis(params.ack_delay_ms),
+        nack_interval: Duration::from_millis(params.nack_interval_ms),
+        retransmit_timeout: Duration::from_millis(params.retransmit_timeout_ms),
+    };
+    let mut tcp = Framed::new(SocketAdapter::new(tcp), flags, timers);
+    tcp.send(Packet::new(PacketType::KeepAlive)).unwrap();
+    if let Some(port) = params.request_port {
+        tcp.send(Packet::new(PacketType::OpenPort).with(&port.to_be_bytes()))
+            .unwrap();
+    }
     let mut sockets: HashMap<u64, SocketAdapter> = HashMap::new();
-    let mut id = 0;
+    let mut pending: HashMap<u64, PendingStream> = HashMap::new();
+    // pending streams get connected on their own thread and are reported here.
+    let connecting: (Sender<Connected>, _) = mpsc::channel();
+    let mut connecting_count = 0;
+    let forwards: Vec<_> = params
+        .local_forwards
+        .iter()
+        .map(|x| {
+            let listener = TcpListener::bind(("127.0.0.1", x.port)).unwrap();
+            (Listener::tcp(listener).unwrap(), x)
+        })
+        .collect();
+    // local connections wait here until the server connected them.
+    let mut opening: HashMap<u64, Connection> = HashMap::new();
+    let mut local_id = 0;
+    let mut upload_limit = (params.upload_limit != 0)
+        .then(|| TokenBucket::new(params.upload_limit as f64, params.upload_limit as f64));
     let mut last_keep_alive = SystemTime::now();
+    let mut last_ping = SystemTime::now();
+    let mut status_modem = params.modem_status_port.map(|port| {
+        let (serial, profile) = open_modem(&params, port);
+        Modem::new(serial, profile, false)
+    });
+    let mut last_modem_status = SystemTime::now();
     loop {
         thread::sleep(Duration::from_millis(params.rate_limit_sleep));
         let mut did_anything = false;
 
-        if last_keep_alive.elapsed().unwrap().as_secs() >= 60 {
+        if last_keep_alive.elapsed().unwrap().as_millis() >= params.peer_timeout_ms as u128 {
             panic!("connection dropped. exiting.");
         }
+        if last_ping.elapsed().unwrap().as_millis() >= params.ping_interval_ms as u128 {
+            tcp.send(Packet::ping()).unwrap();
+            last_ping = SystemTime::now();
+        }
+
+        if params.modem_status_interval != 0
+            && last_modem_status.elapsed().unwrap().as_secs() >= params.modem_status_interval
+        {
+            update_modem_status(&mut tcp.socket, status_modem.as_mut());
+            last_modem_status = SystemTime::now();
+        }
+
+        let now = SystemTime::now();
+        let due: Vec<u64> = pending
+            .iter()
+            .filter(|x| !x.1.connecting && x.1.next_attempt <= now)
+            .map(|x| *x.0)
+            .take(MAX_CONNECTING - connecting_count)
+            .collect();
+        for i in due {
+            did_anything = true;
+            let stream = pending.get_mut(&i).unwrap();
+            stream.connecting = true;
+            let destination = destination(&params, stream);
+            let connected = connecting.0.clone();
+            connecting_count += 1;
+            thread::spawn(move || {
+                let _ = connected.send((i, destination.connect(stream_timeout)));
+            });
+        }
+
+        for (i, result) in connecting.1.try_iter() {
+            did_anything = true;
+            connecting_count -= 1;
+            // the stream may have been closed in the meantime.
+            let Some(stream) = pending.get_mut(&i) else {
+                continue;
+            };
+            stream.connecting = false;
+            match result {
+                Ok(destination) => {
+                    let mut socket = SocketAdapter::new(destination.into());
+                    socket.set_read_limit(params.upload_limit_per_stream);
+                    let _ = socket.internal.set_timeout(stream_timeout);
+                    if is_low_latency(&params, stream) {
+                        let _ = socket.set_low_latency();
+                    }
+                    if stream.target.is_some() {
+                        // the server answers the SOCKS5 peer only now.
+                        tcp.send(Packet::new(PacketType::StreamOpened).with(&i.to_be_bytes()))
+                            .unwrap();
+                    } else if let Some(version) = params.proxy_protocol {
+                        let _ = socket.write_later(&version.header(stream.addresses));
+                    }
+                    let _ = socket.write_later(&stream.data);
+                    if stream.write_done {
+                        let _ = socket.shutdown_write();
+                    }
+                    pending.remove(&i);
+                    sockets.insert(i, socket);
+                }
+                Err(_) if stream.attempts < params.connect_retries => {
+                    let delay = params
+                        .connect_retry_delay_ms
+                        .saturating_mul(1 << stream.attempts.min(16));
+                    stream.attempts += 1;
+                    stream.next_attempt =
+                        SystemTime::now() + Duration::from_millis(delay).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => {
+                    println!();
+                    eprintln!("Unable to forward connection #{i}: {e}");
+                    pending.remove(&i);
+                    let reason = RefuseReason::from(e.kind());
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&i.to_be_bytes())
+                            .with(&[reason.ordinal() as u8]),
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        for (forward, new) in forwards
+            .iter()
+            .filter_map(|(listener, forward)| Some((forward, listener.accept()?.0)))
+        {
+            let i = LOCAL_STREAM | (local_id, local_id += 1).0;
+            println!();
+            println!(
+                "Forwarding local connection #{} to {}:{}.",
+                i & !LOCAL_STREAM,
+                forward.host,
+                forward.host_port
+            );
+            opening.insert(i, new);
+            tcp.send(
+                Packet::new(PacketType::OpenStream)
+                    .with(&i.to_be_bytes())
+                    .with_target(forward.host, forward.host_port),
+            )
+            .unwrap();
+            did_anything = true;
+        }
 
         let mut to_remove = vec![];
-        for (&i, socket) in sockets.iter_mut() {
-            if let Ok(x) = socket.poll(&mut buf) {
+        let mut finished = vec![];
+        let streams = sockets.len();
+        // low-latency streams are read first, so they get their share of the upload limit.
+        let mut order: Vec<u64> = sockets.keys().copied().collect();
+        order.sort_by_key(|i| !sockets[i].is_low_latency());
+        for (n, i) in order.into_iter().enumerate() {
+            let socket = sockets.get_mut(&i).unwrap();
+            let max = match upload_limit {
+                Some(ref mut limit) => limit.share(streams - n).min(buf.len()),
+                None => buf.len(),
+            };
+            if let Ok(x) = socket.poll(&mut buf[..max]) {
                 if let Some(len) = x {
+                    if let Some(ref mut limit) = upload_limit {
+                        limit.consume(len as f64);
+                    }
                     if len == 0 {
-                        to_remove.push(i);
-                    } else {
-                        tcp.write(&[PacketType::ServerData.ordinal() as u8])
+                        // the server may still send, only this direction is done.
+                        tcp.send(Packet::new(PacketType::ShutdownWrite).with(&i.to_be_bytes()))
                             .unwrap();
-                        tcp.write(&i.to_be_bytes()).unwrap();
-                        tcp.write(&(len as u32).to_be_bytes()).unwrap();
-                        tcp.write(&buf[..len]).unwrap();
+                        if socket.is_finished() {
+                            finished.push(i);
+                        }
+                    } else {
+                        tcp.send(
+                            Packet::new(PacketType::ServerData)
+                                .with(&i.to_be_bytes())
+                                .with(&buf[..len]),
+                        )
+                        .unwrap();
                     }
                     did_anything = true;
                 }
@@ -172,73 +564,159 @@ pub fn client(params: ClientParams) {
                 did_anything = true;
             }
             if let x @ 1.. = socket.clear_delay() {
-                tcp.write(&[PacketType::ClientExceededBuffer.ordinal() as u8])
-                    .unwrap();
-                tcp.write(&i.to_be_bytes()).unwrap();
-                tcp.write(&x.to_be_bytes()).unwrap();
+                tcp.send(
+                    Packet::new(PacketType::ClientExceededBuffer)
+                        .with(&i.to_be_bytes())
+                        .with(&x.to_be_bytes()),
+                )
+                .unwrap();
                 socket.punish(x);
             }
         }
         for i in to_remove.into_iter().rev() {
-            tcp.write(&[PacketType::CloseClient.ordinal() as u8])
+            tcp.send(Packet::new(PacketType::CloseClient).with(&i.to_be_bytes()))
                 .unwrap();
-            tcp.write(&i.to_be_bytes()).unwrap();
             if let Some(x) = sockets.remove(&i) {
                 let _ = x.internal.close();
             }
         }
-
-        tcp.update().unwrap();
-        if tcp.poll_exact(&mut buf1).unwrap().is_none() {
-            if !did_anything {
-                thread::sleep(Duration::from_millis(params.sleep_delay_ms));
+        for i in finished {
+            if let Some(x) = sockets.remove(&i) {
+                let _ = x.internal.close();
             }
-            continue;
         }
 
-        let Some(pt) = PacketType::from_ordinal(buf1[0] as i8) else {
-            resync(&mut tcp, &mut id);
-            continue;
+        tcp.socket.update().unwrap();
+        let (pt, data) = match tcp.poll().unwrap() {
+            Some(Received::Packet(pt, data)) => (pt, data),
+            Some(Received::Lost(reason)) => {
+                reset(&mut tcp, &reason, &mut sockets, &mut pending, &mut opening);
+                continue;
+            }
+            None if !did_anything && sockets.values().any(|x| x.is_low_latency()) => {
+                // streams aren't read while the upload limit is used up, so they can't end the wait.
+                let limited = upload_limit.as_mut().is_some_and(|x| x.share(1) == 0);
+                let mut waiting = vec![&mut tcp.socket];
+                if !limited {
+                    waiting.extend(sockets.values_mut());
+                }
+                wait_readable(waiting, Duration::from_millis(params.sleep_delay_ms));
+                continue;
+            }
+            None => {
+                if !did_anything {
+                    thread::sleep(Duration::from_millis(params.sleep_delay_ms));
+                }
+                continue;
+            }
         };
+        // the frame's length was checked, so the fixed fields can always be read.
+        let mut data = &data[..];
         match pt {
             PacketType::NewClient => {
-                let tcp = SocketAdapter::new(Connection::new_tcp(
-                    TcpStream::connect((params.dest_ip, params.dest_port)).unwrap(),
-                    false,
-                ));
-                sockets.insert((id, id += 1).0, tcp);
+                data.read_exact(&mut buf8).unwrap();
+                let id = u64::from_be_bytes(buf8);
+                let Ok(addresses) = read_addresses(&mut data) else {
+                    let reason = "malformed packet";
+                    reset(&mut tcp, reason, &mut sockets, &mut pending, &mut opening);
+                    continue;
+                };
+                let info = StreamInfo::new(id, addresses);
+                println!();
+                println!("New connection {info}.");
+                if let Some(on_new_stream) = params.on_new_stream {
+                    on_new_stream(&info);
+                }
+                // connected at the start of the next round, so failures can be retried.
+                let stream = PendingStream {
+                    addresses,
+                    target: None,
+                    service: None,
+                    attempts: 0,
+                    next_attempt: SystemTime::now(),
+                    connecting: false,
+                    data: Vec::new(),
+                    write_done: false,
+                };
+                if is_low_latency(&params, &stream) {
+                    tcp.send(Packet::new(PacketType::LowLatency).with(&buf8))
+                        .unwrap();
+                }
+                pending.insert(id, stream);
             }
 
             PacketType::CloseClient => {
-                tcp.read_now(&mut buf8).unwrap();
-                if let Some(x) = sockets.remove(&u64::from_be_bytes(buf8)) {
+                data.read_exact(&mut buf8).unwrap();
+                let idx = u64::from_be_bytes(buf8);
+                pending.remove(&idx);
+                opening.remove(&idx);
+                if let Some(x) = sockets.remove(&idx) {
                     let _ = x.internal.close();
                 }
             }
 
+            PacketType::ShutdownWrite => {
+                data.read_exact(&mut buf8).unwrap();
+                let idx = u64::from_be_bytes(buf8);
+                if let Some(stream) = pending.get_mut(&idx) {
+                    stream.write_done = true;
+                }
+                let result = sockets.get_mut(&idx).map(|x| {
+                    let result = x.shutdown_write();
+                    result.map(|()| x.is_finished())
+                });
+                if let Some(Err(_)) = result {
+                    // the destination is gone, so the server's end has to be closed, too.
+                    tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))
+                        .unwrap();
+                }
+                if let Some(Err(_) | Ok(true)) = result {
+                    if let Some(x) = sockets.remove(&idx) {
+                        let _ = x.internal.close();
+                    }
+                }
+            }
+
             PacketType::KeepAlive => {
                 last_keep_alive = SystemTime::now();
-                tcp.write(&[PacketType::KeepAlive.ordinal() as u8]).unwrap();
+            }
+
+            PacketType::Ping => {
+                last_keep_alive = SystemTime::now();
+                tcp.send(Packet::new(PacketType::Pong).with(data)).unwrap();
+            }
+
+            PacketType::Pong => {
+                last_keep_alive = SystemTime::now();
+                tcp.set_rtt(round_trip(data));
             }
 
             PacketType::ClientData => {
-                tcp.read_now(&mut buf8).unwrap();
+                data.read_exact(&mut buf8).unwrap();
                 let idx = u64::from_be_bytes(buf8);
-                tcp.read_now(&mut buf4).unwrap();
-                let len = u32::from_be_bytes(buf4) as usize;
-                tcp.read_now(&mut buf[..len]).unwrap();
-
                 if let Some(socket) = sockets.get_mut(&idx) {
-                    let _ = socket.write_later(&buf[..len]);
+                    let _ = socket.write_later(data);
+                } else if let Some(stream) = pending.get_mut(&idx) {
+                    if stream.data.len() + data.len() > MAX_PENDING_DATA {
+                        println!();
+                        eprintln!("Connection #{idx} sent too much before it was connected.");
+                        pending.remove(&idx);
+                        tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))
+                            .unwrap();
+                    } else {
+                        stream.data.extend_from_slice(data);
+                    }
+                } else {
+                    // e.g. a stream the server opened before it got our reset.
+                    tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))
+                        .unwrap();
                 }
             }
 
-            PacketType::ServerData => resync(&mut tcp, &mut id),
-
             PacketType::ClientExceededBuffer => {
-                tcp.read_now(&mut buf8).unwrap();
+                data.read_exact(&mut buf8).unwrap();
                 let idx = u64::from_be_bytes(buf8);
-                tcp.read_now(&mut buf16).unwrap();
+                data.read_exact(&mut buf16).unwrap();
                 let amount = u128::from_be_bytes(buf16);
 
                 // a single connection doesn't need overuse-penalties
@@ -247,16 +725,153 @@ pub fn client(params: ClientParams) {
                 }
             }
 
-            PacketType::Resync => {
+            PacketType::Reset => {
+                println!();
+                eprintln!("Server lost sync, resetting connections.");
+                drop_streams(&mut sockets, &mut pending, &mut opening);
+            }
+
+            PacketType::StreamRefused => {
+                data.read_exact(&mut buf8).unwrap();
+                let idx = u64::from_be_bytes(buf8);
+                data.read_exact(&mut buf1).unwrap();
+                let reason =
+                    RefuseReason::from_ordinal(buf1[0] as i8).unwrap_or(RefuseReason::Other);
+                println!();
+                eprintln!(
+                    "Server could not forward local connection #{}: {reason}.",
+                    idx & !LOCAL_STREAM
+                );
+                if let Some(x) = opening.remove(&idx) {
+                    let _ = x.abort();
+                }
+            }
+
+            PacketType::PortOpened => {
+                data.read_exact(&mut buf2).unwrap();
+                let port = u16::from_be_bytes(buf2);
+                data.read_exact(&mut buf1).unwrap();
                 println!();
-                tcp.internal.set_print(false);
-                eprintln!("Server asked for re-sync. Waiting 8 seconds, then initiating resync.");
-                thread::sleep(Duration::from_secs(8));
-                resync(&mut tcp, &mut id);
+                match PortStatus::from_ordinal(buf1[0] as i8) {
+                    Some(PortStatus::Opened) => {
+                        println!("Public address: {}:{port}", params.server_ip)
+                    }
+                    Some(status) => eprintln!("Server can't open port {port}: {status}."),
+                    None => eprintln!("Server can't open port {port}."),
+                }
             }
 
-            // this one shouldnt happen.
-            PacketType::ResyncEcho => resync(&mut tcp, &mut id),
+            PacketType::StreamOpened => {
+                data.read_exact(&mut buf8).unwrap();
+                let idx = u64::from_be_bytes(buf8);
+                if let Some(x) = opening.remove(&idx) {
+                    let mut socket = SocketAdapter::new(x);
+                    socket.set_read_limit(params.upload_limit_per_stream);
+                    let _ = socket.internal.set_timeout(stream_timeout);
+                    sockets.insert(idx, socket);
+                } else {
+                    // e.g. a stream we opened before a reset.
+                    tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))
+                        .unwrap();
+                }
+            }
+
+            PacketType::ConnectStream => {
+                data.read_exact(&mut buf8).unwrap();
+                let id = u64::from_be_bytes(buf8);
+                let (Ok(addresses), Ok((host, port))) =
+                    (read_addresses(&mut data), read_target(&mut data))
+                else {
+                    let reason = "malformed packet";
+                    reset(&mut tcp, reason, &mut sockets, &mut pending, &mut opening);
+                    continue;
+                };
+                let info = StreamInfo::new(id, addresses);
+                println!();
+                println!("New connection {info} to {host}:{port}.");
+                if !target_allowed(&params.allow_connect, &host, port) {
+                    eprintln!("Connections to {host}:{port} are not allowed.");
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&buf8)
+                            .with(&[RefuseReason::NotAllowed.ordinal() as u8]),
+                    )
+                    .unwrap();
+                    continue;
+                }
+                if let Some(on_new_stream) = params.on_new_stream {
+                    on_new_stream(&info);
+                }
+                let stream = PendingStream {
+                    addresses,
+                    target: Some((host, port)),
+                    service: None,
+                    attempts: 0,
+                    next_attempt: SystemTime::now(),
+                    connecting: false,
+                    data: Vec::new(),
+                    write_done: false,
+                };
+                if is_low_latency(&params, &stream) {
+                    tcp.send(Packet::new(PacketType::LowLatency).with(&buf8))
+                        .unwrap();
+                }
+                pending.insert(id, stream);
+            }
+
+            PacketType::ServiceStream => {
+                data.read_exact(&mut buf8).unwrap();
+                let id = u64::from_be_bytes(buf8);
+                let (Ok(addresses), Ok(name)) = (read_addresses(&mut data), read_name(&mut data))
+                else {
+                    let reason = "malformed packet";
+                    reset(&mut tcp, reason, &mut sockets, &mut pending, &mut opening);
+                    continue;
+                };
+                let info = StreamInfo::new(id, addresses);
+                println!();
+                println!("New connection {info} to service {name}.");
+                let Some(service) = params.services.iter().position(|x| x.name == name) else {
+                    eprintln!("There is no service {name}.");
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&buf8)
+                            .with(&[RefuseReason::NotFound.ordinal() as u8]),
+                    )
+                    .unwrap();
+                    continue;
+                };
+                if let Some(on_new_stream) = params.on_new_stream {
+                    on_new_stream(&info);
+                }
+                let stream = PendingStream {
+                    addresses,
+                    target: None,
+                    service: Some(service),
+                    attempts: 0,
+                    next_attempt: SystemTime::now(),
+                    connecting: false,
+                    data: Vec::new(),
+                    write_done: false,
+                };
+                if is_low_latency(&params, &stream) {
+                    tcp.send(Packet::new(PacketType::LowLatency).with(&buf8))
+                        .unwrap();
+                }
+                pending.insert(id, stream);
+            }
+
+            // handled by `Framed`
+            PacketType::Ack | PacketType::Nack => (),
+
+            // these can't happen, they should only come from the client
+            PacketType::ServerData
+            | PacketType::OpenPort
+            | PacketType::OpenStream
+            | PacketType::LowLatency => {
+                let reason = "unexpected packet";
+                reset(&mut tcp, reason, &mut sockets, &mut pending, &mut opening);
+            }
         }
     }
 }
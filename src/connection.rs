@@ -5,8 +5,24 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+#[cfg(unix)]
+use std::{
+    mem,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+
 use serial::SerialPort;
 
+#[cfg(unix)]
+use crate::Pipe;
+use crate::{Modem, ModemStatus};
+
+/// How long reads and writes may block, unless changed with `Connection::set_timeout`.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+
 trait ReadWrite: Write + Read + 'static {}
 impl<T> ReadWrite for T where T: Write + Read + 'static {}
 
@@ -19,15 +35,40 @@ enum PrintStatus {
     },
 }
 
+impl PrintStatus {
+    fn new(print: bool) -> Self {
+        if print {
+            PrintStatus::Yes {
+                last_print: SystemTime::now(),
+                bytes: 0,
+                last_bytes: 0,
+            }
+        } else {
+            PrintStatus::No
+        }
+    }
+}
+
 pub struct Connection {
     readwrite: Box<dyn ReadWrite>,
     data: NonNull<()>,
-    set_nonblocking_thunk: fn(NonNull<()>, bool) -> io::Result<()>,
+    set_nonblocking_thunk: fn(NonNull<()>, bool, Duration) -> io::Result<()>,
+    timeout_thunk: fn(NonNull<()>, Duration) -> io::Result<()>,
     close_thunk: fn(NonNull<()>) -> io::Result<()>,
+    shutdown_write_thunk: fn(NonNull<()>) -> io::Result<()>,
+    abort_thunk: Option<fn(NonNull<()>) -> io::Result<()>>,
+    nodelay_thunk: fn(NonNull<()>, bool) -> io::Result<()>,
+    modem_status_thunk: Option<fn(NonNull<()>) -> io::Result<ModemStatus>>,
+    /// The fd that becomes readable when there is something to read, if there is one.
+    #[cfg(unix)]
+    fd: Option<RawFd>,
     is_nb: bool,
     is_serial: bool,
+    timeout: Duration,
     print: bool,
     print_status: PrintStatus,
+    status_note: Option<String>,
+    rtt: Option<Duration>,
 }
 
 impl Write for Connection {
@@ -51,7 +92,8 @@ impl Read for Connection {
         let len = buf.len();
         while !buf.is_empty() {
             match self.read(buf) {
-                Ok(0) if self.is_nb && buf.len() == len => {
+                // serial ports return nothing when no data is there, sockets only on EOF.
+                Ok(0) if self.is_nb && self.is_serial && buf.len() == len => {
                     return Err(io::Error::new(ErrorKind::WouldBlock, "would block"))
                 }
                 Ok(0) => break,
@@ -76,70 +118,160 @@ impl Read for Connection {
 
 impl Connection {
     pub fn new_tcp(stream: TcpStream, print: bool) -> Self {
-        stream
-            .set_read_timeout(Some(Duration::from_secs(20)))
-            .unwrap();
-        stream
-            .set_write_timeout(Some(Duration::from_secs(20)))
-            .unwrap();
+        #[cfg(unix)]
+        let fd = stream.as_raw_fd();
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+        stream.set_write_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
         let mut stream = Box::new(stream);
         Connection {
             data: NonNull::from(stream.as_mut()).cast(),
             readwrite: stream,
-            set_nonblocking_thunk: |data, nb| unsafe {
+            set_nonblocking_thunk: |data, nb, _timeout| unsafe {
                 data.cast::<TcpStream>().as_ref().set_nonblocking(nb)
             },
+            timeout_thunk: |data, timeout| unsafe {
+                let stream = data.cast::<TcpStream>();
+                stream.as_ref().set_read_timeout(Some(timeout))?;
+                stream.as_ref().set_write_timeout(Some(timeout))
+            },
             close_thunk: |data| unsafe {
                 data.cast::<TcpStream>().as_ref().shutdown(Shutdown::Both)
             },
+            shutdown_write_thunk: |data| unsafe {
+                data.cast::<TcpStream>().as_ref().shutdown(Shutdown::Write)
+            },
+            #[cfg(unix)]
+            abort_thunk: Some(|data| unsafe {
+                reset_on_close(data.cast::<TcpStream>().as_ref().as_raw_fd())
+            }),
+            #[cfg(not(unix))]
+            abort_thunk: None,
+            nodelay_thunk: |data, nodelay| unsafe {
+                data.cast::<TcpStream>().as_ref().set_nodelay(nodelay)
+            },
+            modem_status_thunk: None,
+            #[cfg(unix)]
+            fd: Some(fd),
             is_nb: false,
             is_serial: false,
+            timeout: DEFAULT_TIMEOUT,
             print: true,
-            print_status: if print {
-                PrintStatus::Yes {
-                    last_print: SystemTime::now(),
-                    bytes: 0,
-                    last_bytes: 0,
-                }
-            } else {
-                PrintStatus::No
+            print_status: PrintStatus::new(print),
+            status_note: None,
+            rtt: None,
+        }
+    }
+    #[cfg(unix)]
+    pub fn new_unix(stream: UnixStream, print: bool) -> Self {
+        let fd = stream.as_raw_fd();
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+        stream.set_write_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+        let mut stream = Box::new(stream);
+        Connection {
+            data: NonNull::from(stream.as_mut()).cast(),
+            readwrite: stream,
+            set_nonblocking_thunk: |data, nb, _timeout| unsafe {
+                data.cast::<UnixStream>().as_ref().set_nonblocking(nb)
+            },
+            timeout_thunk: |data, timeout| unsafe {
+                let stream = data.cast::<UnixStream>();
+                stream.as_ref().set_read_timeout(Some(timeout))?;
+                stream.as_ref().set_write_timeout(Some(timeout))
+            },
+            close_thunk: |data| unsafe {
+                data.cast::<UnixStream>().as_ref().shutdown(Shutdown::Both)
             },
+            shutdown_write_thunk: |data| unsafe {
+                data.cast::<UnixStream>().as_ref().shutdown(Shutdown::Write)
+            },
+            abort_thunk: None,
+            nodelay_thunk: |_data, _nodelay| Ok(()),
+            modem_status_thunk: None,
+            fd: Some(fd),
+            is_nb: false,
+            is_serial: false,
+            timeout: DEFAULT_TIMEOUT,
+            print: true,
+            print_status: PrintStatus::new(print),
+            status_note: None,
+            rtt: None,
         }
     }
-    pub fn new_serial<T: SerialPort + 'static>(mut serial: T, print: bool) -> Self {
-        serial.set_timeout(Duration::from_secs(20)).unwrap();
-        let mut serial = Box::new(serial);
+    pub fn new_modem<T: SerialPort + 'static>(mut modem: Modem<T>, print: bool) -> Self {
+        modem.set_timeout(DEFAULT_TIMEOUT).unwrap();
+        let mut modem = Box::new(modem);
         Connection {
-            data: NonNull::from(serial.as_mut()).cast(),
-            readwrite: serial,
-            set_nonblocking_thunk: |data, nb| unsafe {
-                data.cast::<T>()
+            data: NonNull::from(modem.as_mut()).cast(),
+            readwrite: modem,
+            set_nonblocking_thunk: |data, nb, timeout| unsafe {
+                data.cast::<Modem<T>>()
                     .as_mut()
-                    .set_timeout(Duration::from_secs(if nb { 0 } else { 20 }))
+                    .set_timeout(if nb { Duration::ZERO } else { timeout })
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::ConnectionAborted, "serial port went down")
+                    })
+            },
+            timeout_thunk: |data, timeout| unsafe {
+                data.cast::<Modem<T>>()
+                    .as_mut()
+                    .set_timeout(timeout)
                     .map_err(|_| {
                         io::Error::new(io::ErrorKind::ConnectionAborted, "serial port went down")
                     })
             },
             // no need to close this.
             close_thunk: |_data| Ok(()),
+            shutdown_write_thunk: |_data| Ok(()),
+            abort_thunk: None,
+            nodelay_thunk: |_data, _nodelay| Ok(()),
+            modem_status_thunk: Some(|data| unsafe { data.cast::<Modem<T>>().as_mut().status() }),
+            // the modem keeps what it read past its messages, which its fd knows nothing about.
+            #[cfg(unix)]
+            fd: None,
             is_nb: false,
             is_serial: true,
+            timeout: DEFAULT_TIMEOUT,
             print: true,
-            print_status: if print {
-                PrintStatus::Yes {
-                    last_print: SystemTime::now(),
-                    bytes: 0,
-                    last_bytes: 0,
-                }
-            } else {
-                PrintStatus::No
+            print_status: PrintStatus::new(print),
+            status_note: None,
+            rtt: None,
+        }
+    }
+    #[cfg(unix)]
+    pub fn new_pipe(pipe: Pipe, print: bool) -> Self {
+        let fd = pipe.as_raw_fd();
+        let mut pipe = Box::new(pipe);
+        Connection {
+            data: NonNull::from(pipe.as_mut()).cast(),
+            readwrite: pipe,
+            set_nonblocking_thunk: |data, nb, _timeout| unsafe {
+                data.cast::<Pipe>().as_mut().set_nonblocking(nb);
+                Ok(())
+            },
+            timeout_thunk: |data, timeout| unsafe {
+                data.cast::<Pipe>().as_mut().set_timeout(timeout);
+                Ok(())
             },
+            // the pipes are closed when dropped.
+            close_thunk: |_data| Ok(()),
+            shutdown_write_thunk: |_data| Ok(()),
+            abort_thunk: None,
+            nodelay_thunk: |_data, _nodelay| Ok(()),
+            modem_status_thunk: None,
+            fd: Some(fd),
+            is_nb: false,
+            is_serial: false,
+            timeout: DEFAULT_TIMEOUT,
+            print: true,
+            print_status: PrintStatus::new(print),
+            status_note: None,
+            rtt: None,
         }
     }
-    fn as_read(&mut self) -> &mut (dyn Read) {
+    fn as_read(&mut self) -> &mut dyn Read {
         &mut self.readwrite
     }
-    fn as_write(&mut self) -> &mut (dyn Write) {
+    fn as_write(&mut self) -> &mut dyn Write {
         &mut self.readwrite
     }
     #[allow(dead_code)]
@@ -151,12 +283,47 @@ impl Connection {
             return Ok(());
         }
         self.is_nb = nonblocking;
-        (self.set_nonblocking_thunk)(self.data, nonblocking)
+        (self.set_nonblocking_thunk)(self.data, nonblocking, self.timeout)
+    }
+
+    /// Sets how long reads and writes may block.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        // serial ports only have one timeout, which is set when they block again.
+        if self.is_serial && self.is_nb {
+            return Ok(());
+        }
+        (self.timeout_thunk)(self.data, timeout)
     }
     pub fn close(&self) -> io::Result<()> {
         (self.close_thunk)(self.data)
     }
 
+    /// Tells the peer nothing more will be written, while what it sends can still be read.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        (self.shutdown_write_thunk)(self.data)
+    }
+
+    /// Makes the peer see a reset instead of a normal close once this is dropped, if supported.
+    /// Otherwise, this is the same as `close`.
+    pub fn abort(&self) -> io::Result<()> {
+        match self.abort_thunk {
+            Some(thunk) => thunk(self.data),
+            None => self.close(),
+        }
+    }
+
+    /// Sets TCP_NODELAY, if this is a tcp connection.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        (self.nodelay_thunk)(self.data, nodelay)
+    }
+
+    /// The fd to wait on for something to read, if there is one.
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
     pub fn is_serial(&self) -> bool {
         self.is_serial
     }
@@ -165,6 +332,22 @@ impl Connection {
         self.print = print;
     }
 
+    /// Leaves transparent mode to ask the modem about its link, if this is a modem connection.
+    pub fn modem_status(&mut self) -> Option<io::Result<ModemStatus>> {
+        self.modem_status_thunk.map(|thunk| thunk(self.data))
+    }
+
+    /// Sets extra information that is shown after the transfer speed.
+    pub fn set_status_note(&mut self, note: Option<String>) {
+        self.status_note = note;
+    }
+
+    /// Sets the last round-trip time measured on this connection, shown after the transfer
+    /// speed.
+    pub fn set_rtt(&mut self, rtt: Option<Duration>) {
+        self.rtt = rtt;
+    }
+
     fn print_status(&mut self, add: usize) {
         if let &mut PrintStatus::Yes {
             ref mut last_print,
@@ -181,6 +364,12 @@ impl Connection {
                     print!(
                         "\r\x1b[KCurrent transfer speed: {bps}B/s, transferred {total}B so far."
                     );
+                    if let Some(rtt) = self.rtt {
+                        print!(" RTT {}ms.", rtt.as_millis());
+                    }
+                    if let Some(ref note) = self.status_note {
+                        print!(" {note}");
+                    }
                     stdout().flush().unwrap();
                 }
                 *last_bytes = *bytes;
@@ -197,6 +386,27 @@ impl Connection {
     }
 }
 
+#[cfg(unix)]
+fn reset_on_close(fd: RawFd) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 fn to_units(diff: u128) -> String {
     match diff {
         x @ 1_000_000_000_000.. => ((x / 1_000_000_000) as f64 / 1000.0).to_string() + "G",
@@ -0,0 +1,259 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    net::TcpStream,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const ESCAPE: &[u8] = b"+++";
+const ESCAPE_GUARD: Duration = Duration::from_millis(1000);
+
+/// A simulated SIMCom-style AT modem on a pseudo-terminal. It understands the commands used by
+/// the built-in modem profiles and bridges to a real TCP port once a connection is opened.
+pub struct FakeModem {
+    master: File,
+    // kept open so the pty stays usable between two clients.
+    _slave: File,
+    path: String,
+    echo: bool,
+    online: bool,
+    tcp: Option<TcpStream>,
+    line: Vec<u8>,
+    to_modem: Vec<u8>,
+    to_tcp: Vec<u8>,
+    last_rx: SystemTime,
+    escape_since: Option<SystemTime>,
+}
+
+impl FakeModem {
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            let slave = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(&path)?;
+            let mut termios = mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0
+                || libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                master,
+                _slave: slave,
+                path,
+                echo: true,
+                online: false,
+                tcp: None,
+                line: Vec::new(),
+                to_modem: Vec::new(),
+                to_tcp: Vec::new(),
+                last_rx: SystemTime::now(),
+                escape_since: None,
+            })
+        }
+    }
+
+    /// The device path to hand to the client as modem port.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Runs the modem forever. If `target` is set, every connection is bridged there instead of
+    /// the address given in the connect command.
+    pub fn run(mut self, target: Option<&str>) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut did_anything = false;
+
+            match self.master.read(&mut buf) {
+                Ok(len @ 1..) => {
+                    self.receive(&buf[..len], target);
+                    did_anything = true;
+                }
+                Ok(0) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+
+            if let Some(since) = self.escape_since {
+                if since.elapsed().unwrap() >= ESCAPE_GUARD {
+                    println!("Escaped to command mode.");
+                    self.escape_since = None;
+                    self.online = false;
+                    self.respond("OK");
+                }
+            }
+
+            if let Some(tcp) = &mut self.tcp {
+                let mut closed = false;
+                if self.online {
+                    match tcp.read(&mut buf) {
+                        Ok(0) => closed = true,
+                        Ok(len) => {
+                            self.to_modem.extend_from_slice(&buf[..len]);
+                            did_anything = true;
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                        Err(_) => closed = true,
+                    }
+                }
+                match tcp.write(&self.to_tcp) {
+                    Ok(len) => {
+                        self.to_tcp.drain(..len);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                    Err(_) => closed = true,
+                }
+                if closed {
+                    println!("Connection closed by remote.");
+                    self.tcp = None;
+                    self.online = false;
+                    self.to_tcp.clear();
+                    self.respond("CLOSED");
+                }
+            }
+
+            match self.master.write(&self.to_modem) {
+                Ok(len) => {
+                    self.to_modem.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+
+            if !did_anything {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn receive(&mut self, data: &[u8], target: Option<&str>) {
+        if self.online {
+            if self.escape_since.take().is_some() {
+                self.to_tcp.extend_from_slice(ESCAPE);
+            }
+            if data == ESCAPE && self.last_rx.elapsed().unwrap() >= ESCAPE_GUARD {
+                self.escape_since = Some(SystemTime::now());
+            } else {
+                self.to_tcp.extend_from_slice(data);
+            }
+            self.last_rx = SystemTime::now();
+            return;
+        }
+        for (i, &b) in data.iter().enumerate() {
+            if self.echo {
+                self.to_modem.push(b);
+            }
+            match b {
+                b'\r' | b'\n' => {
+                    let line = String::from_utf8_lossy(&self.line).trim().to_owned();
+                    self.line.clear();
+                    if !line.is_empty() {
+                        self.command(&line, target);
+                    }
+                    if self.online {
+                        // the rest is already meant for the connection.
+                        let rest = &data[i + 1..];
+                        self.to_tcp
+                            .extend_from_slice(rest.strip_prefix(b"\n").unwrap_or(rest));
+                        break;
+                    }
+                }
+                b => self.line.push(b),
+            }
+        }
+        self.last_rx = SystemTime::now();
+    }
+
+    fn respond(&mut self, response: &str) {
+        self.to_modem
+            .extend_from_slice(format!("\r\n{response}\r\n").as_bytes());
+    }
+
+    fn command(&mut self, line: &str, target: Option<&str>) {
+        println!("> {line}");
+        let upper = line.to_ascii_uppercase();
+        let (cmd, args) = upper.split_once('=').unwrap_or((&upper, ""));
+        let response = match cmd {
+            "AT" => "OK".to_owned(),
+            "ATE0" | "ATE1" => {
+                self.echo = cmd == "ATE1";
+                "OK".to_owned()
+            }
+            "ATO" if self.tcp.is_some() => {
+                self.online = true;
+                "CONNECT 115200".to_owned()
+            }
+            "ATO" => "NO CARRIER".to_owned(),
+            "AT+CIPMODE" | "AT+CGDCONT" | "AT+CSTT" | "AT+CIICR" | "AT+IFC" => "OK".to_owned(),
+            "AT+NETOPEN" => "OK\r\n\r\n+NETOPEN: 0".to_owned(),
+            "AT+NETCLOSE" => "OK\r\n\r\n+NETCLOSE: 0".to_owned(),
+            "AT+CFUN?" => "+CFUN: 1\r\n\r\nOK".to_owned(),
+            "AT+CPIN?" => "+CPIN: READY\r\n\r\nOK".to_owned(),
+            "AT+IPADDR" => "+IPADDR: 10.64.0.2\r\n\r\nOK".to_owned(),
+            "AT+CIFSR" => "10.64.0.2".to_owned(),
+            "AT+CSQ" => "+CSQ: 21,99\r\n\r\nOK".to_owned(),
+            "AT+CREG?" => "+CREG: 0,1\r\n\r\nOK".to_owned(),
+            "AT+COPS?" => "+COPS: 0,0,\"Fake Mobile\",7\r\n\r\nOK".to_owned(),
+            "AT+CIPCLOSE" if self.tcp.take().is_some() => "OK\r\n\r\nCLOSED".to_owned(),
+            "AT+CIPCLOSE" => "ERROR".to_owned(),
+            "AT+CIPOPEN" | "AT+CIPSTART" => {
+                // original case is needed for hostnames, so split the untouched line.
+                let args: Vec<_> = line[line.len() - args.len()..]
+                    .split(',')
+                    .map(|x| x.trim().trim_matches('"'))
+                    .collect();
+                let addr = match (target, args.as_slice()) {
+                    (Some(target), _) => target.to_owned(),
+                    (None, [.., ip, port]) => format!("{ip}:{port}"),
+                    _ => {
+                        self.respond("ERROR");
+                        return;
+                    }
+                };
+                match TcpStream::connect(&addr) {
+                    Ok(tcp) => {
+                        println!("Bridging to {addr}.");
+                        tcp.set_nonblocking(true).unwrap();
+                        self.tcp = Some(tcp);
+                        self.online = true;
+                        self.last_rx = SystemTime::now();
+                        "OK\r\n\r\nCONNECT 115200".to_owned()
+                    }
+                    Err(e) => {
+                        println!("Unable to connect to {addr}: {e}");
+                        "CONNECT FAIL".to_owned()
+                    }
+                }
+            }
+            _ => "ERROR".to_owned(),
+        };
+        self.respond(&response);
+    }
+}
@@ -0,0 +1,617 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::{Duration, SystemTime},
+};
+
+use crate::{Packet, PacketType, SocketAdapter, CHECKSUMS, RELIABLE};
+
+/// Every frame starts with this, so the start of the next one can be found again after garbage
+/// on the line.
+const MARKER: [u8; 2] = [0xa5, 0x5a];
+
+/// Longer frames can only be garbage, and longer packets are never sent. Stream data goes in
+/// chunks of 1024 bytes, so real packets stay far below this.
+const MAX_FRAME: usize = 65536;
+
+/// Frames further ahead of (or behind) the one expected are taken for garbage, too.
+const MAX_AHEAD: u32 = 4096;
+
+/// How many bytes of sent frames are kept for retransmission. Older ones are given up on, and if
+/// they are asked for, both ends reset their streams.
+const RETRANSMIT_BUFFER: usize = 256 * 1024;
+
+/// Acknowledge after this many frames, or when the first unacknowledged one is
+/// `ArqTimers::ack_delay` old.
+const ACK_EVERY: u32 = 16;
+
+/// The most frames asked for in one Nack.
+const MAX_NACK: usize = 256;
+
+/// The CRC32 lookup table, for the reversed polynomial 0xedb88320.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    !parts.iter().copied().flatten().fold(!0u32, |crc, &x| {
+        CRC_TABLE[((crc ^ x as u32) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+/// Puts `packet` in a frame with the sequence number `seq`.
+fn frame(seq: u32, packet: &[u8], checksums: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(packet.len() + 14);
+    frame.extend_from_slice(&MARKER);
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    frame.extend_from_slice(packet);
+    if checksums {
+        let crc = crc32(&[&frame[MARKER.len()..]]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+    }
+    frame
+}
+
+/// How long the ARQ waits before acknowledging, asking for frames again and sending them again.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ArqTimers {
+    /// Acknowledge once the first unacknowledged frame is this old.
+    pub ack_delay: Duration,
+    /// Ask for missing frames again if they still haven't arrived after this long.
+    pub nack_interval: Duration,
+    /// Send the oldest unacknowledged frame again if nothing was acknowledged for this long after
+    /// it left the write buffer, in case the last frames of a burst were lost and nothing comes
+    /// after them. Once a round-trip time was measured, the timeout follows from that instead.
+    pub retransmit_timeout: Duration,
+}
+
+/// What `Framed::poll` received.
+pub(crate) enum Received {
+    /// The next packet: its type and its fields.
+    Packet(PacketType, Vec<u8>),
+    /// Frames were lost, so the streams can't be trusted anymore and have to be reset.
+    Lost(String),
+}
+
+/// The state of the ARQ: lost frames are asked for again instead of resetting the streams.
+struct Reliable {
+    /// Frames that weren't acknowledged yet, oldest first, with their sequence numbers.
+    unacked: VecDeque<(u32, Vec<u8>)>,
+    /// The total length of `unacked`.
+    unacked_len: usize,
+    /// Since when everything was written without an acknowledgement freeing frames, which starts
+    /// the retransmission timer. Frames still in the write buffer can't have been lost yet.
+    flushed: Option<SystemTime>,
+    /// The sequence number of the last Reset sent because frames that were asked for weren't
+    /// buffered anymore.
+    skip_to: Option<u32>,
+    /// Frames that arrived after a gap, waiting for the missing ones.
+    early: HashMap<u32, (PacketType, Vec<u8>)>,
+    /// How far missing frames have been asked for already.
+    nacked_up_to: u32,
+    last_nack: SystemTime,
+    /// Frames received since the last acknowledgement, and when the first of them arrived.
+    not_acked: u32,
+    first_not_acked: SystemTime,
+}
+
+impl Reliable {
+    fn new() -> Self {
+        Self {
+            unacked: VecDeque::new(),
+            unacked_len: 0,
+            flushed: None,
+            skip_to: None,
+            early: HashMap::new(),
+            nacked_up_to: 0,
+            last_nack: SystemTime::now(),
+            not_acked: 0,
+            first_not_acked: SystemTime::now(),
+        }
+    }
+
+    fn keep(&mut self, seq: u32, frame: Vec<u8>) {
+        self.unacked_len += frame.len();
+        self.unacked.push_back((seq, frame));
+        while self.unacked_len > RETRANSMIT_BUFFER {
+            let (_, frame) = self.unacked.pop_front().unwrap();
+            self.unacked_len -= frame.len();
+        }
+    }
+
+    fn received(&mut self) {
+        if self.not_acked == 0 {
+            self.first_not_acked = SystemTime::now();
+        }
+        self.not_acked += 1;
+    }
+}
+
+/// The packets on a tunnel, each in a frame: the marker, a sequence number, the length, the
+/// packet and, if checksums are on, a CRC32 of all but the marker. A frame that doesn't follow the
+/// previous one shows exactly where the two ends lost sync.
+///
+/// On reliable tunnels, lost frames are asked for again with a Nack and sent again from a buffer,
+/// and Acks tell the other end which ones it doesn't have to keep anymore. Acks and Nacks are
+/// frames outside the sequence, so they are never acknowledged or sent again themselves.
+pub(crate) struct Framed {
+    pub socket: SocketAdapter,
+    /// The sequence number of the next frame sent.
+    sent: u32,
+    /// The sequence number of the next frame expected.
+    received: u32,
+    /// Whether a frame was cut off, so it isn't known which one comes next.
+    cut_off: bool,
+    /// What was read after a false marker, scanned again before anything new is read.
+    unread: VecDeque<u8>,
+    checksums: bool,
+    reliable: Option<Reliable>,
+    timers: ArqTimers,
+    /// The last round-trip time measured with a ping.
+    rtt: Option<Duration>,
+}
+
+impl Framed {
+    /// `flags` are those agreed on in the handshake. `timers` only matter on reliable tunnels.
+    pub fn new(socket: SocketAdapter, flags: u32, timers: ArqTimers) -> Self {
+        let reliable = flags & RELIABLE != 0;
+        Self {
+            socket,
+            sent: 0,
+            received: 0,
+            cut_off: false,
+            unread: VecDeque::new(),
+            // without checksums, a damaged frame can't be told apart from a good one.
+            checksums: flags & CHECKSUMS != 0 || reliable,
+            reliable: reliable.then(Reliable::new),
+            timers,
+            rtt: None,
+        }
+    }
+
+    /// Records a round-trip time measured with a ping. It is shown in the status line and times
+    /// retransmissions.
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+        self.socket.internal.set_rtt(Some(rtt));
+    }
+
+    /// How long to wait for an acknowledgement: the round trip, with the same again as margin,
+    /// plus the time the other end may hold it back, taken to be the same as here.
+    fn retransmit_timeout(&self) -> Duration {
+        self.rtt.map_or(self.timers.retransmit_timeout, |rtt| {
+            2 * rtt + self.timers.ack_delay
+        })
+    }
+
+    pub fn send(&mut self, packet: Packet) -> io::Result<()> {
+        // the other end would take it for garbage and hunt for a marker in its contents.
+        if packet.0.len() > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet too large for a frame",
+            ));
+        }
+        let frame = frame(self.sent, &packet.0, self.checksums);
+        self.socket.write(&frame)?;
+        if let Some(reliable) = &mut self.reliable {
+            reliable.keep(self.sent, frame);
+        }
+        self.sent = self.sent.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Sends an Ack or Nack. These carry the sequence number of the next frame, but don't use it
+    /// up.
+    fn send_control(&mut self, packet: Packet) -> io::Result<()> {
+        let frame = frame(self.sent, &packet.0, self.checksums);
+        self.socket.write(&frame)
+    }
+
+    fn ack(&mut self) -> io::Result<()> {
+        if let Some(reliable) = &mut self.reliable {
+            reliable.not_acked = 0;
+        }
+        self.send_control(Packet::new(PacketType::Ack).with(&self.received.to_be_bytes()))
+    }
+
+    /// Asks for the missing frames up to `until`, starting at `from`.
+    fn nack(&mut self, from: u32, until: u32) -> io::Result<()> {
+        let Some(reliable) = &mut self.reliable else {
+            return Ok(());
+        };
+        let mut packet = Packet::new(PacketType::Nack);
+        let mut count = 0;
+        let mut seq = from;
+        while seq != until && count < MAX_NACK {
+            if !reliable.early.contains_key(&seq) {
+                packet = packet.with(&seq.to_be_bytes());
+                count += 1;
+            }
+            seq = seq.wrapping_add(1);
+        }
+        reliable.nacked_up_to = seq;
+        reliable.last_nack = SystemTime::now();
+        if count == 0 {
+            return Ok(());
+        }
+        self.send_control(packet)
+    }
+
+    /// Handles an Ack or Nack. Returns `Lost` if frames were asked for that aren't kept anymore,
+    /// and the Reset the caller sends then lets the other end skip them.
+    fn control(&mut self, kind: PacketType, data: &[u8]) -> io::Result<Option<Received>> {
+        let Some(reliable) = &mut self.reliable else {
+            return Ok(None);
+        };
+        let mut seqs = data
+            .chunks_exact(4)
+            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]));
+        if kind == PacketType::Ack {
+            let Some(next) = seqs.next() else {
+                return Ok(None);
+            };
+            // everything before `next` arrived.
+            while let Some((seq, frame)) = reliable.unacked.front() {
+                if next.wrapping_sub(*seq) as i32 <= 0 {
+                    break;
+                }
+                reliable.unacked_len -= frame.len();
+                reliable.unacked.pop_front();
+                reliable.flushed = None;
+            }
+            return Ok(None);
+        }
+        let mut gone = 0;
+        for seq in seqs {
+            if let Some((_, frame)) = reliable.unacked.iter().find(|x| x.0 == seq) {
+                self.socket.write_later(frame)?;
+            } else if self.sent.wrapping_sub(seq) as i32 > 0
+                // a Reset after this one is on its way already.
+                && reliable
+                    .skip_to
+                    .is_none_or(|skip_to| skip_to.wrapping_sub(seq) as i32 <= 0)
+            {
+                gone += 1;
+            }
+        }
+        self.socket.update()?;
+        if gone > 0 {
+            // the caller's Reset comes next. It lets the other end skip the frames it waits for.
+            reliable.skip_to = Some(self.sent);
+            return Ok(Some(Received::Lost(format!(
+                "{gone} frames no longer buffered"
+            ))));
+        }
+        Ok(None)
+    }
+
+    /// Acknowledges, asks for missing frames again and sends unacknowledged ones again when it's
+    /// time to, and hands out frames that arrived early once the ones before them are there.
+    fn tick(&mut self) -> io::Result<Option<Received>> {
+        let retransmit_timeout = self.retransmit_timeout();
+        let timers = self.timers;
+        let Some(reliable) = &mut self.reliable else {
+            return Ok(None);
+        };
+        if let Some((kind, data)) = reliable.early.remove(&self.received) {
+            reliable.received();
+            self.received = self.received.wrapping_add(1);
+            return Ok(Some(Received::Packet(kind, data)));
+        }
+        let elapsed = |time: SystemTime| time.elapsed().unwrap_or_default();
+        if reliable.not_acked >= ACK_EVERY
+            || reliable.not_acked > 0 && elapsed(reliable.first_not_acked) >= timers.ack_delay
+        {
+            self.ack()?;
+        }
+        let Some(reliable) = &mut self.reliable else {
+            return Ok(None);
+        };
+        if !reliable.early.is_empty() && elapsed(reliable.last_nack) >= timers.nack_interval {
+            let until = reliable.nacked_up_to;
+            self.nack(self.received, until)?;
+        }
+        let Some(reliable) = &mut self.reliable else {
+            return Ok(None);
+        };
+        if reliable.unacked.is_empty() || !self.socket.is_flushed() {
+            reliable.flushed = None;
+        } else if elapsed(*reliable.flushed.get_or_insert_with(SystemTime::now))
+            >= retransmit_timeout
+        {
+            // the timer starts again once this left the write buffer, too.
+            reliable.flushed = None;
+            self.socket.write(&reliable.unacked[0].1)?;
+        }
+        Ok(None)
+    }
+
+    /// Returns false if the rest of a frame didn't arrive in time.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let unread = buf.len().min(self.unread.len());
+        for (x, y) in buf.iter_mut().zip(self.unread.drain(..unread)) {
+            *x = y;
+        }
+        Ok(unread == buf.len() || self.socket.read_now(&mut buf[unread..])?.is_some())
+    }
+
+    /// Puts back what was read after a false marker, so a real frame in it isn't lost. Returns
+    /// how many bytes that were.
+    fn unread(&mut self, parts: &[&[u8]]) -> usize {
+        for &x in parts.iter().flat_map(|x| x.iter()).rev() {
+            self.unread.push_front(x);
+        }
+        parts.iter().map(|x| x.len()).sum()
+    }
+
+    fn lose_frame(&mut self) -> io::Result<Option<Received>> {
+        // the next frame shows which one is missing, and it is asked for again.
+        if self.reliable.is_some() {
+            return Ok(None);
+        }
+        self.cut_off = true;
+        Ok(Some(Received::Lost("frame cut off".into())))
+    }
+
+    /// Receives the next frame if one is arriving. Anything that isn't a frame is skipped up to
+    /// the next marker.
+    pub fn poll(&mut self) -> io::Result<Option<Received>> {
+        if let Some(received) = self.tick()? {
+            return Ok(Some(received));
+        }
+        let mut byte = [0u8; 1];
+        match self.unread.pop_front() {
+            Some(x) => byte[0] = x,
+            None if self.socket.poll_exact(&mut byte)?.is_none() => return Ok(None),
+            None => (),
+        }
+        let mut marker = [0, byte[0]];
+        let mut read = 1;
+        loop {
+            if !self.read(&mut byte)? {
+                return self.lose_frame();
+            }
+            read += 1;
+            marker = [marker[1], byte[0]];
+            if marker != MARKER {
+                continue;
+            }
+            let mut header = [0u8; 8];
+            if !self.read(&mut header)? {
+                return self.lose_frame();
+            }
+            read += header.len();
+            let seq = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+            let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            let ahead = seq.wrapping_sub(self.received);
+            let behind = self.received.wrapping_sub(seq);
+            if !(1..=MAX_FRAME).contains(&len)
+                || ahead > MAX_AHEAD
+                    && (self.reliable.is_none() || behind > MAX_AHEAD)
+                    && !self.cut_off
+            {
+                read -= self.unread(&[&header]);
+                continue;
+            }
+            let mut data = vec![0u8; len];
+            if !self.read(&mut data)? {
+                return self.lose_frame();
+            }
+            read += len;
+            let mut crc = [0u8; 4];
+            let crc_len = if self.checksums { crc.len() } else { 0 };
+            if self.checksums {
+                if !self.read(&mut crc)? {
+                    return self.lose_frame();
+                }
+                read += crc.len();
+            }
+            // a corrupted frame is garbage, too.
+            let kind = PacketType::from_ordinal(data[0] as i8)
+                .filter(|x| len > x.min_len())
+                .filter(|_| !self.checksums || u32::from_be_bytes(crc) == crc32(&[&header, &data]));
+            let Some(kind) = kind else {
+                read -= self.unread(&[&header, &data, &crc[..crc_len]]);
+                continue;
+            };
+
+            let skipped = read - MARKER.len() - header.len() - len - crc_len;
+            if skipped > 0 {
+                eprintln!();
+                eprintln!("Skipped {skipped} bytes of garbage on the tunnel.");
+            }
+            if matches!(kind, PacketType::Ack | PacketType::Nack) {
+                return self.control(kind, &data[1..]);
+            }
+            data.remove(0);
+            if let Some(reliable) = &mut self.reliable {
+                if ahead > MAX_AHEAD {
+                    // sent again, but it had arrived after all. The Ack was lost, so send it
+                    // again.
+                    reliable.not_acked = ACK_EVERY;
+                } else if ahead > 0 && kind == PacketType::Reset {
+                    // the other end gave up on the missing frames, and their streams are dropped
+                    // now anyway. What came before the Reset belonged to them, too.
+                    let next = seq.wrapping_add(1);
+                    reliable
+                        .early
+                        .retain(|&x, _| x.wrapping_sub(next) <= MAX_AHEAD);
+                    reliable.received();
+                    self.received = next;
+                    return Ok(Some(Received::Packet(kind, data)));
+                } else if ahead > 0 {
+                    reliable.early.insert(seq, (kind, data));
+                    // ask for the frames in the gap, unless that was done already.
+                    let from = if reliable.nacked_up_to.wrapping_sub(self.received) <= MAX_AHEAD {
+                        reliable.nacked_up_to
+                    } else {
+                        self.received
+                    };
+                    if from.wrapping_sub(self.received) <= ahead {
+                        self.nack(from, seq.wrapping_add(1))?;
+                    }
+                } else {
+                    reliable.received();
+                    self.received = seq.wrapping_add(1);
+                    return Ok(Some(Received::Packet(kind, data)));
+                }
+                return Ok(None);
+            }
+            self.received = seq.wrapping_add(1);
+            // after a cut-off frame, the streams were reset already.
+            let resumed = std::mem::take(&mut self.cut_off);
+            if ahead != 0 && !resumed {
+                return Ok(Some(Received::Lost(format!("{ahead} frames lost"))));
+            }
+            return Ok(Some(Received::Packet(kind, data)));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    use super::*;
+    use crate::Connection;
+
+    const TIMERS: ArqTimers = ArqTimers {
+        ack_delay: Duration::from_millis(200),
+        nack_interval: Duration::from_secs(2),
+        retransmit_timeout: Duration::from_secs(3),
+    };
+
+    /// A `Framed` receiving what is written to the other end of the pair.
+    fn framed(flags: u32) -> (Framed, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new_unix(ours, false);
+        connection.set_timeout(Duration::from_millis(100)).unwrap();
+        (
+            Framed::new(SocketAdapter::new(connection), flags, TIMERS),
+            theirs,
+        )
+    }
+
+    /// A frame with stream data for stream 0.
+    fn frame(flags: u32, seq: u32, data: &[u8]) -> Vec<u8> {
+        let packet = Packet::new(PacketType::ClientData)
+            .with(&0u64.to_be_bytes())
+            .with(data);
+        super::frame(seq, &packet.0, flags & (CHECKSUMS | RELIABLE) != 0)
+    }
+
+    fn data(received: Option<Received>) -> Vec<u8> {
+        match received {
+            Some(Received::Packet(PacketType::ClientData, data)) => data[8..].to_vec(),
+            Some(Received::Packet(kind, _)) => panic!("got a {kind:?} packet"),
+            Some(Received::Lost(reason)) => panic!("frames lost: {reason}"),
+            None => panic!("nothing received"),
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(&[b"123456789"]), 0xcbf43926);
+        assert_eq!(crc32(&[b"1234", b"", b"56789"]), 0xcbf43926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        let (mut framed, mut other) = framed(CHECKSUMS);
+        other.write_all(&[1, 2, 0xa5, 0xa5, 3]).unwrap();
+        other.write_all(&frame(CHECKSUMS, 0, b"one")).unwrap();
+        // a marker with a length that can't be right.
+        other
+            .write_all(&[0xa5, 0x5a, 0, 0, 0, 1, 0xff, 0, 0, 0])
+            .unwrap();
+        let mut damaged = frame(CHECKSUMS, 1, b"two");
+        damaged[11] ^= 1;
+        other.write_all(&damaged).unwrap();
+        other.write_all(&frame(CHECKSUMS, 1, b"three")).unwrap();
+        assert_eq!(data(framed.poll().unwrap()), b"one");
+        assert_eq!(data(framed.poll().unwrap()), b"three");
+        assert!(framed.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn frames_after_false_markers_are_found() {
+        let (mut framed, mut other) = framed(CHECKSUMS);
+        other.write_all(&[0xa5, 0x5a]).unwrap();
+        other.write_all(&frame(CHECKSUMS, 0, b"one")).unwrap();
+        // a marker whose frame would take in most of the next one.
+        other
+            .write_all(&[0xa5, 0x5a, 0, 0, 0, 1, 0, 0, 0, 20])
+            .unwrap();
+        other.write_all(&frame(CHECKSUMS, 1, b"two")).unwrap();
+        assert_eq!(data(framed.poll().unwrap()), b"one");
+        assert_eq!(data(framed.poll().unwrap()), b"two");
+        assert!(framed.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn gaps_are_reported() {
+        let (mut framed, mut other) = framed(0);
+        other.write_all(&frame(0, 0, b"one")).unwrap();
+        other.write_all(&frame(0, 3, b"four")).unwrap();
+        other.write_all(&frame(0, 4, b"five")).unwrap();
+        assert_eq!(data(framed.poll().unwrap()), b"one");
+        assert!(matches!(framed.poll().unwrap(), Some(Received::Lost(x)) if x == "2 frames lost"));
+        assert_eq!(data(framed.poll().unwrap()), b"five");
+    }
+
+    #[test]
+    fn gaps_are_filled_when_reliable() {
+        let (mut framed, mut other) = framed(RELIABLE);
+        other.write_all(&frame(RELIABLE, 1, b"two")).unwrap();
+        other.write_all(&frame(RELIABLE, 2, b"three")).unwrap();
+        assert!(framed.poll().unwrap().is_none());
+        assert!(framed.poll().unwrap().is_none());
+        other.write_all(&frame(RELIABLE, 0, b"one")).unwrap();
+        assert_eq!(data(framed.poll().unwrap()), b"one");
+        assert_eq!(data(framed.poll().unwrap()), b"two");
+        assert_eq!(data(framed.poll().unwrap()), b"three");
+        // the gap was asked for with a Nack, and frames sent again twice are dropped.
+        other.write_all(&frame(RELIABLE, 1, b"two")).unwrap();
+        assert!(framed.poll().unwrap().is_none());
+        assert!(framed.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        for flags in [0, RELIABLE] {
+            let (mut framed, mut other) = framed(flags);
+            framed.received = u32::MAX;
+            other.write_all(&frame(flags, u32::MAX, b"last")).unwrap();
+            other.write_all(&frame(flags, 0, b"first")).unwrap();
+            assert_eq!(data(framed.poll().unwrap()), b"last");
+            assert_eq!(data(framed.poll().unwrap()), b"first");
+            assert_eq!(framed.received, 1);
+        }
+    }
+
+    #[test]
+    fn oversized_packets_are_refused() {
+        let (mut framed, _other) = framed(0);
+        let packet = Packet::new(PacketType::ClientData).with(&[0; MAX_FRAME]);
+        assert!(framed.send(packet).is_err());
+    }
+}
@@ -1,16 +1,40 @@
+mod cidr;
 mod client;
 mod connection;
+#[cfg(target_os = "linux")]
+mod fake_modem;
+mod frame;
+mod listener;
+mod modem;
 mod packet;
+#[cfg(unix)]
+mod pipe;
+mod proxy_protocol;
 mod server;
 mod socket_adapter;
+mod socks;
+mod stream_info;
+mod token_bucket;
+mod vhost;
 
 use std::io::{Error, ErrorKind};
 
+pub use cidr::*;
 pub use client::*;
 pub(crate) use connection::*;
+#[cfg(target_os = "linux")]
+pub use fake_modem::*;
+pub(crate) use frame::*;
+pub(crate) use listener::*;
+pub(crate) use modem::*;
 pub(crate) use packet::*;
+#[cfg(unix)]
+pub(crate) use pipe::*;
+pub use proxy_protocol::*;
 pub use server::*;
 pub(crate) use socket_adapter::*;
+pub use stream_info::*;
+pub(crate) use token_bucket::*;
 
 pub(crate) fn io_sync<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
     match result {
@@ -20,3 +44,41 @@ pub(crate) fn io_sync<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
         Err(x) => Err(x),
     }
 }
+
+/// Whether `host`:`port` matches one of `allowed`, given as host:port where either may be `*`.
+/// IPv6 hosts may be in brackets on either side.
+pub(crate) fn target_allowed(allowed: &[&str], host: &str, port: u16) -> bool {
+    fn unbracket(host: &str) -> &str {
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+    let host = unbracket(host);
+    allowed.iter().any(|x| {
+        let (allowed_host, allowed_port) = match x.rsplit_once(':') {
+            // a bracketed IPv6 host without a port.
+            Some((_, port)) if port.ends_with(']') => (*x, "*"),
+            Some(x) => x,
+            None => (*x, "*"),
+        };
+        let allowed_host = unbracket(allowed_host);
+        (allowed_host == "*" || allowed_host.eq_ignore_ascii_case(host))
+            && (allowed_port == "*" || allowed_port.parse() == Ok(port))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bracketed_ipv6_targets() {
+        assert!(target_allowed(&["[::1]:22"], "::1", 22));
+        assert!(target_allowed(&["[::1]:22"], "[::1]", 22));
+        assert!(!target_allowed(&["[::1]:22"], "::1", 23));
+        assert!(!target_allowed(&["[::2]:22"], "::1", 22));
+        assert!(target_allowed(&["[::1]"], "::1", 443));
+        assert!(target_allowed(&["[::1]:*"], "::1", 443));
+        assert!(target_allowed(&["*:22"], "fe80::1", 22));
+        assert!(target_allowed(&["Example.com:*"], "example.COM", 80));
+        assert!(!target_allowed(&[], "::1", 22));
+    }
+}
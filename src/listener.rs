@@ -0,0 +1,91 @@
+#[cfg(unix)]
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::Path,
+    process,
+};
+use std::{io, net::TcpListener};
+
+use crate::{Addresses, Connection};
+
+/// A listener for public connections.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn tcp(listener: TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::Tcp(listener))
+    }
+
+    #[cfg(unix)]
+    pub fn unix(path: &str, mode: Option<u32>) -> io::Result<Self> {
+        // a socket left over from an earlier run would make bind fail.
+        if fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = match mode {
+            Some(mode) => bind_with_mode(Path::new(path), mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        listener.set_nonblocking(true)?;
+        Ok(Self::Unix(listener))
+    }
+
+    /// Accepts a pending connection, along with its addresses if it has any.
+    pub fn accept(&self) -> Option<(Connection, Option<Addresses>)> {
+        match self {
+            Self::Tcp(x) => {
+                let (stream, peer) = x.accept().ok()?;
+                // without its addresses, it would pass for a peer on the unix socket and skip the
+                // checks, so it is dropped instead.
+                let local = stream.local_addr().ok()?;
+                // listening on :: makes IPv4 peers show up as ::ffff:a.b.c.d, which
+                // Addresses::new undoes.
+                let addresses = Addresses::new(peer, local);
+                Some((Connection::new_tcp(stream, false), Some(addresses)))
+            }
+            #[cfg(unix)]
+            Self::Unix(x) => x
+                .accept()
+                .ok()
+                .map(|(stream, _)| (Connection::new_unix(stream, false), None)),
+        }
+    }
+}
+
+/// Binds a unix socket that nobody can connect to before it has `mode`: it is created in a
+/// directory only we can enter, and moved to `path` once its mode is set.
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "unix socket path has no name"))?;
+    // bind would refuse to replace another file, rename wouldn't.
+    if fs::symlink_metadata(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "unix socket path is taken",
+        ));
+    }
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = dir.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(mode))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    // after the rename, only the empty directory is left.
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
@@ -1,10 +1,155 @@
-use std::env;
+use std::{env, ops::RangeInclusive};
 
-use revpfw3::{client, server, ClientParams};
+#[cfg(target_os = "linux")]
+use revpfw3::FakeModem;
+use revpfw3::{client, server, Balance, ClientParams, LocalForward, ServerParams, Service, Tenant};
+
+const OPTIONS: &[&str] = &[
+    "modem-status",
+    "modem-status-port",
+    "proxy-command",
+    "proxy-protocol",
+    "connect-retries",
+    "connect-retry-delay",
+    "stdio",
+    "public-port",
+    "balance",
+    "tenant",
+    "port-range",
+    "request-port",
+    "local-forward",
+    "allow-forward",
+    "allow-connect",
+    "socks-port",
+    "socks-user",
+    "http-port",
+    "vhost",
+    "http-fallback",
+    "tls-port",
+    "service",
+    "low-latency",
+    "checksums",
+    "reliable",
+    "ping-interval",
+    "peer-timeout",
+    "io-timeout",
+    "stream-timeout",
+    "handshake-timeout",
+    "ack-delay",
+    "nack-interval",
+    "retransmit-timeout",
+    "no-public-tcp",
+    "unix-socket",
+    "unix-socket-mode",
+    "allow",
+    "deny",
+    "max-streams",
+    "max-streams-total",
+    "max-streams-per-ip",
+    "accept-rate",
+    "accept-burst",
+    "upload-limit",
+    "upload-limit-per-stream",
+    "download-limit",
+    "download-limit-per-stream",
+];
+
+fn bytes(s: &str) -> u64 {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1_000),
+        Some((i, 'M' | 'm')) => (&s[..i], 1_000_000),
+        Some((i, 'G' | 'g')) => (&s[..i], 1_000_000_000),
+        _ => (s, 1),
+    };
+    (number.parse::<f64>().unwrap() * unit as f64) as u64
+}
+
+fn port_range(s: &str) -> RangeInclusive<u16> {
+    let (from, to) = s.split_once('-').unwrap_or((s, s));
+    from.parse().unwrap()..=to.parse().unwrap()
+}
+
+fn tenant(s: &str) -> Tenant<'_> {
+    // the key may contain colons, the ports can't.
+    let (key, ports) = s
+        .rsplit_once(':')
+        .expect("tenants are given as <key>:<ports>");
+    let (ranges, ports): (Vec<_>, Vec<_>) = ports.split(',').partition(|x| x.contains('-'));
+    Tenant {
+        key,
+        ports: ports.into_iter().map(|x| x.parse().unwrap()).collect(),
+        port_ranges: ranges.into_iter().map(port_range).collect(),
+    }
+}
+
+fn local_forward(s: &str) -> LocalForward<'_> {
+    let error = "local forwards are given as <port>:<host>:<port>";
+    let (port, target) = s.split_once(':').expect(error);
+    let (host, host_port) = target.rsplit_once(':').expect(error);
+    LocalForward {
+        port: port.parse().unwrap(),
+        host: host.trim_start_matches('[').trim_end_matches(']'),
+        host_port: host_port.parse().unwrap(),
+    }
+}
+
+fn service(s: &str) -> Service<'_> {
+    let (name, destination) = s
+        .split_once('=')
+        .expect("services are given as <name>=<ip>:<port>");
+    // unix sockets don't have a port.
+    let (dest_ip, dest_port) = match destination.starts_with("unix:") {
+        true => (destination, 0),
+        false => {
+            let (ip, port) = destination
+                .rsplit_once(':')
+                .expect("services are given as <name>=<ip>:<port>");
+            (
+                ip.trim_start_matches('[').trim_end_matches(']'),
+                port.parse().unwrap(),
+            )
+        }
+    };
+    Service {
+        name,
+        dest_ip,
+        dest_port,
+        low_latency: false,
+    }
+}
 
 fn main() {
-    let args: Vec<_> = env::args().skip(1).collect();
-    if (6..=11).contains(&args.len()) && args[0] == "client" {
+    let mut args = Vec::new();
+    let mut options = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.strip_prefix("--") {
+            Some(option) => options.push(match option.split_once('=') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (option.to_owned(), None),
+            }),
+            None => args.push(arg),
+        }
+    }
+    let unknown = options.iter().find(|x| !OPTIONS.contains(&x.0.as_str()));
+    let option = |name: &str| {
+        options
+            .iter()
+            .rev()
+            .find(|x| x.0 == name)
+            .and_then(|x| x.1.as_deref())
+    };
+    let flag = |name: &str| options.iter().any(|x| x.0 == name);
+    let values = |name: &'static str| {
+        options
+            .iter()
+            .filter(move |x| x.0 == name)
+            .filter_map(|x| x.1.as_deref())
+    };
+    let repeated = |name| values(name).map(|x| x.parse().unwrap()).collect();
+
+    if let Some((name, _)) = unknown {
+        eprintln!("Unknown option --{name}");
+    } else if (6..=11).contains(&args.len()) && args[0] == "client" {
         client(ClientParams {
             server_ip: &args[1],
             server_port: args[2].parse().unwrap(),
@@ -14,22 +159,176 @@ fn main() {
             sleep_delay_ms: args.get(6).map(|x| x.parse().unwrap()).unwrap_or(1),
             modem_port: args.get(7).map(|x| x.as_str()),
             modem_baud: args.get(8).map(|x| x.parse().unwrap()),
-            modem_init: args.get(9).map(|x| x.as_str()),
+            modem_profile: args.get(9).map(|x| x.as_str()),
             rate_limit_sleep: args.get(10).map(|x| x.parse().unwrap()).unwrap_or(0),
+            modem_status_interval: option("modem-status")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(0),
+            modem_status_port: option("modem-status-port"),
+            proxy_command: option("proxy-command"),
+            upload_limit: option("upload-limit").map_or(0, bytes),
+            upload_limit_per_stream: option("upload-limit-per-stream").map_or(0, bytes),
+            proxy_protocol: option("proxy-protocol").map(|x| x.parse().unwrap()),
+            on_new_stream: None,
+            connect_retries: option("connect-retries").map_or(0, |x| x.parse().unwrap()),
+            connect_retry_delay_ms: option("connect-retry-delay")
+                .map_or(500, |x| x.parse().unwrap()),
+            request_port: option("request-port").map(|x| x.parse().unwrap()),
+            local_forwards: values("local-forward").map(local_forward).collect(),
+            allow_connect: values("allow-connect").collect(),
+            services: values("service")
+                .map(service)
+                .map(|mut x| {
+                    x.low_latency = values("low-latency").any(|name| name == x.name);
+                    x
+                })
+                .collect(),
+            low_latency: options
+                .iter()
+                .any(|x| x.0 == "low-latency" && x.1.is_none()),
+            checksums: flag("checksums"),
+            reliable: flag("reliable"),
+            ping_interval_ms: option("ping-interval").map_or(10000, |x| x.parse().unwrap()),
+            peer_timeout_ms: option("peer-timeout").map_or(60000, |x| x.parse().unwrap()),
+            io_timeout_ms: option("io-timeout").map_or(20000, |x| x.parse().unwrap()),
+            stream_timeout_ms: option("stream-timeout").map_or(20000, |x| x.parse().unwrap()),
+            ack_delay_ms: option("ack-delay").map_or(200, |x| x.parse().unwrap()),
+            nack_interval_ms: option("nack-interval").map_or(2000, |x| x.parse().unwrap()),
+            retransmit_timeout_ms: option("retransmit-timeout")
+                .map_or(3000, |x| x.parse().unwrap()),
+        });
+    } else if (3..=4).contains(&args.len()) && args[0] == "server" {
+        server(ServerParams {
+            port: args[1].parse().unwrap(),
+            key: &args[2],
+            sleep_delay_ms: args.get(3).map(|x| x.parse().unwrap()).unwrap_or(1),
+            stdio: flag("stdio"),
+            public_port: option("public-port").map(|x| x.parse().unwrap()),
+            balance: option("balance").map_or(Balance::RoundRobin, |x| x.parse().unwrap()),
+            public_tcp: !flag("no-public-tcp"),
+            unix_socket: option("unix-socket"),
+            unix_socket_mode: option("unix-socket-mode")
+                .map(|x| u32::from_str_radix(x, 8).unwrap()),
+            allow: repeated("allow"),
+            deny: repeated("deny"),
+            max_streams: option("max-streams").map_or(0, |x| x.parse().unwrap()),
+            max_streams_total: option("max-streams-total").map_or(0, |x| x.parse().unwrap()),
+            max_streams_per_ip: option("max-streams-per-ip").map_or(0, |x| x.parse().unwrap()),
+            accept_rate: option("accept-rate").map_or(0.0, |x| x.parse().unwrap()),
+            accept_burst: option("accept-burst").map_or(1.0, |x| x.parse().unwrap()),
+            download_limit: option("download-limit").map_or(0, bytes),
+            download_limit_per_stream: option("download-limit-per-stream").map_or(0, bytes),
+            port_ranges: values("port-range").map(port_range).collect(),
+            tenants: values("tenant").map(tenant).collect(),
+            allow_forward: values("allow-forward").collect(),
+            socks_port: option("socks-port").map(|x| x.parse().unwrap()),
+            socks_users: values("socks-user")
+                .map(|x| {
+                    x.split_once(':')
+                        .expect("SOCKS users are given as <user>:<password>")
+                })
+                .collect(),
+            http_port: option("http-port").map(|x| x.parse().unwrap()),
+            vhosts: values("vhost")
+                .map(|x| {
+                    x.split_once('=')
+                        .expect("vhosts are given as <host>=<service>")
+                })
+                .collect(),
+            http_fallback_status: option("http-fallback").map_or(404, |x| x.parse().unwrap()),
+            tls_port: option("tls-port").map(|x| x.parse().unwrap()),
+            checksums: flag("checksums"),
+            reliable: flag("reliable"),
+            ping_interval_ms: option("ping-interval").map_or(10000, |x| x.parse().unwrap()),
+            peer_timeout_ms: option("peer-timeout").map_or(60000, |x| x.parse().unwrap()),
+            io_timeout_ms: option("io-timeout").map_or(20000, |x| x.parse().unwrap()),
+            stream_timeout_ms: option("stream-timeout").map_or(20000, |x| x.parse().unwrap()),
+            handshake_timeout_ms: option("handshake-timeout").map_or(20000, |x| x.parse().unwrap()),
+            ack_delay_ms: option("ack-delay").map_or(200, |x| x.parse().unwrap()),
+            nack_interval_ms: option("nack-interval").map_or(2000, |x| x.parse().unwrap()),
+            retransmit_timeout_ms: option("retransmit-timeout")
+                .map_or(3000, |x| x.parse().unwrap()),
         });
     }
-    if (3..=4).contains(&args.len()) && args[0] == "server" {
-        server(
-            args[1].parse().unwrap(),
-            &args[2],
-            if args.len() == 4 {
-                args[3].parse().unwrap()
-            } else {
-                1
-            },
-        );
+    #[cfg(target_os = "linux")]
+    if unknown.is_none() && (1..=2).contains(&args.len()) && args[0] == "fakemodem" {
+        let modem = FakeModem::open().unwrap();
+        println!("Fake modem listening on {}", modem.path());
+        modem.run(args.get(1).map(|x| x.as_str())).unwrap();
     }
     eprintln!("Usage: \n\
-               \x20 revpfw3 server <port> <key> [<poll delay>]\n\
-               \x20 revpfw3 client <server ip> <server port> <destination ip> <destination port> <key> [<poll delay> [<modem port> <modem baud> <modem init file>]]");
+               \x20 revpfw3 server <port> <key> [<poll delay>] [options]\n\
+               \x20 revpfw3 client <server ip> <server port> <destination ip> <destination port> <key> [<poll delay> [<modem port> <modem baud> <modem profile>]] [options]\n\
+               \x20 revpfw3 fakemodem [<bridge to host:port>]\n\
+               \n\
+               The destination ip may be unix:<path> to forward to a unix socket, the destination port is then ignored.\n\
+               \n\
+               Server options:\n\
+               \x20 --stdio                       talk to the client through stdin/stdout instead of <port>\n\
+               \x20 --public-port=<port>          accept public connections here, any number of clients may then connect on <port>\n\
+               \x20 --balance=<strategy>         round-robin (default) or least-streams, to spread connections over clients\n\
+               \x20 --port-range=<from>-<to>     ports clients may open themselves, may be repeated\n\
+               \x20 --tenant=<key>:<ports>       serve another key on its own comma-separated public ports, may be repeated.\n\
+               \x20                              <from>-<to> entries are ports its clients may open themselves\n\
+               \x20 --allow-forward=<host>:<port> let clients forward local ports there, * matches anything, may be repeated\n\
+               \x20 --socks-port=<port>          accept SOCKS5 connections here, the client connects them where they ask\n\
+               \x20 --socks-user=<user>:<password> require SOCKS5 peers to log in, may be repeated\n\
+               \x20 --http-port=<port>           accept HTTP connections here and route them by host to a service of the client\n\
+               \x20 --vhost=<host>=<service>     route requests for host, which may start with *., to a service, may be repeated\n\
+               \x20 --http-fallback=<status>     response status for unknown hosts, default 404, 421 is another good choice\n\
+               \x20 --tls-port=<port>            accept TLS connections here and route them by server name like --vhost, without\n\
+               \x20                              decrypting them\n\
+               \x20 --no-public-tcp               don't accept public connections on the public port\n\
+               \x20 --unix-socket=<path>          accept public connections on this unix socket\n\
+               \x20 --unix-socket-mode=<mode>     permissions of the unix socket, in octal\n\
+               \x20 --allow=<network>             only accept public connections from this network, may be repeated\n\
+               \x20 --deny=<network>              reject public connections from this network, may be repeated\n\
+               \x20 --max-streams=<count>         maximum number of concurrent public connections per key\n\
+               \x20 --max-streams-total=<count>   maximum number of concurrent public connections of all keys\n\
+               \x20 --max-streams-per-ip=<count>  maximum number of concurrent public connections per ip\n\
+               \x20 --accept-rate=<per second>    maximum rate of new public connections per ip\n\
+               \x20 --accept-burst=<count>        new public connections per ip allowed at once, default 1\n\
+               \x20 --download-limit=<bytes>      bytes per second sent to the client, K, M and G may be appended\n\
+               \x20 --download-limit-per-stream=<bytes>\n\
+               \x20 --checksums                   check every frame with a CRC32, even if the client doesn't ask for it\n\
+               \x20 --reliable                    send lost frames again, even if the client doesn't ask for it\n\
+               \x20 --handshake-timeout=<ms>      how long SOCKS5, HTTP and TLS peers get to say where they want to go,\n\
+               \x20                              default 20000\n\
+               \n\
+               Options for both:\n\
+               \x20 --ping-interval=<ms>         how often the other end is pinged to measure the round-trip time,\n\
+               \x20                              default 10000\n\
+               \x20 --peer-timeout=<ms>          give up on the other end if it wasn't heard from for this long,\n\
+               \x20                              default 60000\n\
+               \x20 --io-timeout=<ms>            how long reading or writing the tunnel may block, default 20000\n\
+               \x20 --stream-timeout=<ms>        how long reading or writing a forwarded connection may block,\n\
+               \x20                              default 20000\n\
+               \x20 --ack-delay=<ms>             with --reliable, how long received frames may wait for their\n\
+               \x20                              acknowledgement, default 200\n\
+               \x20 --nack-interval=<ms>         with --reliable, how often missing frames are asked for, default 2000\n\
+               \x20 --retransmit-timeout=<ms>    with --reliable, how long unacknowledged frames wait before they are\n\
+               \x20                              sent again until the round-trip time is known, default 3000\n\
+               \n\
+               Client options:\n\
+               \x20 --proxy-command=<command>     talk to the server through a command, %h and %p are replaced\n\
+               \x20 --proxy-protocol=<v1|v2>      send a PROXY protocol header with the public address to the destination\n\
+               \x20 --request-port=<port>        ask the server to open this public port, 0 for any allowed one\n\
+               \x20 --local-forward=<port>:<host>:<port>\n\
+               \x20                              carry connections to this local port to host:port as seen from the server\n\
+               \x20 --allow-connect=<host>:<port> let SOCKS5 peers of the server connect there, * matches anything,\n\
+               \x20                              may be repeated\n\
+               \x20 --service=<name>=<ip>:<port>  where connections the server routes to a service go, may be repeated\n\
+               \x20 --low-latency[=<service>]   forward connections to the destination, or a service, without delay, for\n\
+               \x20                              ssh, games and other interactive protocols, may be repeated\n\
+               \x20 --connect-retries=<count>    retry connecting to the destination before giving up on a connection\n\
+               \x20 --connect-retry-delay=<ms>   delay before the first retry, doubled for every further one up to a minute, default 500\n\
+               \x20 --upload-limit=<bytes>        bytes per second sent to the server, K, M and G may be appended\n\
+               \x20 --upload-limit-per-stream=<bytes>\n\
+               \x20 --checksums                   check every frame with a CRC32 against line noise, always on for modems\n\
+               \x20 --reliable                    send lost frames again instead of resetting connections, for noisy\n\
+               \x20                              serial links and modems\n\
+               \x20 --modem-status=<seconds>      query signal and registration of the modem periodically\n\
+               \x20 --modem-status-port=<port>    use this second AT port for status queries\n\
+               \n\
+               Built-in modem profiles: SIM800, SIM7XXX. A path to a profile file may be given instead.");
 }
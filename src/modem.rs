@@ -0,0 +1,486 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serial::{BaudRate, CharSize, FlowControl, Parity, PortSettings, SerialPort, StopBits};
+
+pub(crate) const DEFAULT_PORT_SETTINGS: PortSettings = PortSettings {
+    baud_rate: BaudRate::Baud115200,
+    char_size: CharSize::Bits8,
+    parity: Parity::ParityNone,
+    stop_bits: StopBits::Stop1,
+    flow_control: FlowControl::FlowNone,
+};
+
+const BUILTIN_PROFILES: [(&str, &str); 2] = [
+    ("SIM800", include_str!("../modemfiles/SIM800.profile")),
+    ("SIM7XXX", include_str!("../modemfiles/SIM7XXX.profile")),
+];
+
+/// A line of a script in a modem profile.
+#[derive(Clone)]
+pub(crate) enum Step {
+    /// `WAIT <ms>`.
+    Wait(Duration),
+    /// A command sent to the modem, in which $IP, $PORT and $APN are replaced.
+    Command(String),
+}
+
+/// Describes how to talk to one family of modems. See modemfiles/ for the format.
+#[derive(Clone)]
+pub(crate) struct ModemProfile {
+    pub settings: PortSettings,
+    pub apn: String,
+    pub init: Vec<Step>,
+    pub hangup: Vec<Step>,
+    pub connect: Vec<Step>,
+    pub escape: String,
+    pub escape_guard: Duration,
+    pub closed: Vec<String>,
+}
+
+impl Default for ModemProfile {
+    fn default() -> Self {
+        Self {
+            settings: DEFAULT_PORT_SETTINGS,
+            apn: String::new(),
+            init: Vec::new(),
+            hangup: Vec::new(),
+            connect: Vec::new(),
+            escape: String::new(),
+            escape_guard: Duration::from_millis(1000),
+            closed: Vec::new(),
+        }
+    }
+}
+
+impl ModemProfile {
+    /// Loads a built-in profile by name, or a profile file by path.
+    pub fn load(name: &str) -> Result<Self, String> {
+        if let Some((_, text)) = BUILTIN_PROFILES
+            .iter()
+            .find(|x| x.0.eq_ignore_ascii_case(name))
+        {
+            return Self::parse(text);
+        }
+        Self::parse(
+            &fs::read_to_string(name)
+                .map_err(|e| format!("unable to read modem profile {name}: {e}"))?,
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut profile = Self::default();
+        let mut section = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: &str| format!("line {}: {e}", n + 1);
+            if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                section = Some(name);
+                continue;
+            }
+            let script = match section {
+                None => {
+                    let (key, value) = line
+                        .split_once('=')
+                        .ok_or_else(|| err("expected key = value"))?;
+                    profile.set(key.trim(), value.trim()).map_err(|e| err(&e))?;
+                    continue;
+                }
+                Some("init") => &mut profile.init,
+                Some("hangup") => &mut profile.hangup,
+                Some("connect") => &mut profile.connect,
+                Some(x) => return Err(err(&format!("unknown section [{x}]"))),
+            };
+            script.push(match line.strip_prefix("WAIT ") {
+                Some(ms) => Step::Wait(Duration::from_millis(
+                    ms.trim()
+                        .parse()
+                        .map_err(|_| err(&format!("invalid wait time {ms}")))?,
+                )),
+                None => Step::Command(line.to_owned()),
+            });
+        }
+        Ok(profile)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {key}: {value}");
+        match key {
+            "baud" => {
+                self.settings.baud_rate =
+                    BaudRate::from_speed(value.parse().map_err(|_| invalid())?)
+            }
+            "data_bits" => {
+                self.settings.char_size = match value {
+                    "5" => CharSize::Bits5,
+                    "6" => CharSize::Bits6,
+                    "7" => CharSize::Bits7,
+                    "8" => CharSize::Bits8,
+                    _ => return Err(invalid()),
+                }
+            }
+            "parity" => {
+                self.settings.parity = match value {
+                    "none" => Parity::ParityNone,
+                    "odd" => Parity::ParityOdd,
+                    "even" => Parity::ParityEven,
+                    _ => return Err(invalid()),
+                }
+            }
+            "stop_bits" => {
+                self.settings.stop_bits = match value {
+                    "1" => StopBits::Stop1,
+                    "2" => StopBits::Stop2,
+                    _ => return Err(invalid()),
+                }
+            }
+            "flow_control" => {
+                self.settings.flow_control = match value {
+                    "none" => FlowControl::FlowNone,
+                    "xonxoff" => FlowControl::FlowSoftware,
+                    "rtscts" => FlowControl::FlowHardware,
+                    _ => return Err(invalid()),
+                }
+            }
+            "apn" => self.apn = value.to_owned(),
+            "escape" => self.escape = value.to_owned(),
+            "escape_guard" => {
+                self.escape_guard = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+            }
+            "closed" => self.closed.push(value.to_owned()),
+            _ => return Err(format!("unknown key {key}")),
+        }
+        Ok(())
+    }
+
+    /// Leaves transparent mode in case the modem is still in it.
+    pub fn escape<T: SerialPort>(&self, serial: &mut T) -> io::Result<String> {
+        if self.escape.is_empty() {
+            return Ok(String::new());
+        }
+        thread::sleep(self.escape_guard);
+        serial.write_all(self.escape.as_bytes())?;
+        thread::sleep(self.escape_guard);
+        read_response(serial, Duration::from_millis(200))
+    }
+
+    pub fn run_script<T: SerialPort>(
+        &self,
+        serial: &mut T,
+        script: &[Step],
+        ip: &str,
+        port: u16,
+    ) -> io::Result<String> {
+        let mut response = String::new();
+        for step in script {
+            let line = match step {
+                Step::Wait(time) => {
+                    thread::sleep(*time);
+                    continue;
+                }
+                Step::Command(line) => line,
+            };
+            let line = line
+                .replace("$IP", ip)
+                .replace("$PORT", &port.to_string())
+                .replace("$APN", &self.apn);
+            println!("> {line}");
+            serial.write_all((line + "\r\n").as_bytes())?;
+            response += &read_response(serial, Duration::from_millis(200))?;
+            thread::sleep(Duration::from_millis(300));
+        }
+        Ok(response)
+    }
+
+    /// Brings the modem from any state into a transparent connection to ip:port.
+    pub fn dial<T: SerialPort>(&self, serial: &mut T, ip: &str, port: u16) -> io::Result<()> {
+        self.escape(serial)?;
+        self.run_script(serial, &self.init, ip, port)?;
+        self.run_script(serial, &self.hangup, ip, port)?;
+        let response = self.run_script(serial, &self.connect, ip, port)?
+            + &read_response(serial, Duration::from_millis(5000))?;
+        if let Some(code) = self.closed.iter().find(|x| response.contains(x.as_str())) {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("modem reported {code} while connecting"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn read_response<T: SerialPort>(serial: &mut T, timeout: Duration) -> io::Result<String> {
+    serial.set_timeout(timeout)?;
+    let mut s = Vec::new();
+    let _ = serial.read_to_end(&mut s).is_ok();
+    let s = String::from_utf8_lossy(&s).into_owned();
+    if !s.is_empty() {
+        println!("< {}", s.replace('\n', "\n< ").trim());
+    }
+    Ok(s)
+}
+
+/// A modem port that can leave and re-enter transparent mode without losing tunnel data.
+pub(crate) struct Modem<T> {
+    port: T,
+    profile: ModemProfile,
+    online: bool,
+    timeout: Duration,
+    pending: Vec<u8>,
+}
+
+impl<T: SerialPort> Read for Modem<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return self.port.read(buf);
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl<T: SerialPort> Write for Modem<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<T: SerialPort> Modem<T> {
+    /// `online` is whether the port carries the tunnel in transparent mode, as opposed to
+    /// being a second AT port that is only used for status queries.
+    pub fn new(port: T, profile: ModemProfile, online: bool) -> Self {
+        Self {
+            timeout: port.timeout(),
+            port,
+            profile,
+            online,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(self.port.set_timeout(timeout)?)
+    }
+
+    pub fn status(&mut self) -> io::Result<ModemStatus> {
+        // even if leaving transparent mode failed, the modem may have left it, so it is told to
+        // resume whenever the escape sequence went out.
+        let (escaped, left) = match self.online {
+            true => self.leave_data_mode(),
+            false => (false, Ok(())),
+        };
+        let status = left.and_then(|()| self.query());
+        let resumed = match (self.online, escaped) {
+            (true, true) => self.resume(),
+            (true, false) => Ok(()),
+            (false, _) => {
+                self.pending.clear();
+                Ok(())
+            }
+        };
+        let restored = self.port.set_timeout(self.timeout).map_err(io::Error::from);
+        both(both(status, resumed), restored)
+    }
+
+    fn query(&mut self) -> io::Result<ModemStatus> {
+        Ok(ModemStatus {
+            signal: field(&self.command("AT+CSQ")?, "+CSQ:", 0).and_then(|x| x.parse().ok()),
+            registration: field(&self.command("AT+CREG?")?, "+CREG:", 1)
+                .and_then(|x| x.parse().ok()),
+            operator: field(&self.command("AT+COPS?")?, "+COPS:", 2).map(str::to_owned),
+        })
+    }
+
+    /// Returns whether the escape sequence was sent, and whether the modem confirmed it.
+    fn leave_data_mode(&mut self) -> (bool, io::Result<()>) {
+        if self.profile.escape.is_empty() {
+            return (
+                false,
+                Err(io::Error::other("modem profile has no escape sequence")),
+            );
+        }
+        // everything that arrives before the modem confirms the escape still belongs to the
+        // tunnel.
+        let mut data = match self.read_for(self.profile.escape_guard) {
+            Ok(data) => data,
+            Err(e) => return (false, Err(e)),
+        };
+        if let Err(e) = self.port.write_all(self.profile.escape.as_bytes()) {
+            self.pending.extend_from_slice(&data);
+            return (false, Err(e));
+        }
+        let result = self
+            .read_for(self.profile.escape_guard * 2)
+            .map(|x| data.extend(x));
+        let ok = data.windows(6).rposition(|x| x == b"\r\nOK\r\n");
+        self.pending
+            .extend_from_slice(&data[..ok.unwrap_or(data.len())]);
+        let confirmed = result.and_then(|()| {
+            ok.map(|_| ())
+                .ok_or_else(|| io::Error::other("modem did not leave transparent mode"))
+        });
+        (true, confirmed)
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        let response = self.command("ATO")?;
+        if let Some(code) = self
+            .profile
+            .closed
+            .iter()
+            .find(|x| response.contains(x.as_str()))
+        {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("modem reported {code}"),
+            ));
+        }
+        if !response.contains("CONNECT") {
+            return Err(io::Error::other("modem did not resume the connection"));
+        }
+        Ok(())
+    }
+
+    fn read_for(&mut self, duration: Duration) -> io::Result<Vec<u8>> {
+        let start = SystemTime::now();
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        self.port.set_timeout(Duration::from_millis(50))?;
+        while start.elapsed().unwrap() < duration {
+            match self.port.read(&mut buf) {
+                Ok(len) => data.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Sends a command and reads up to and including its final result code. Anything after it
+    /// is kept for the tunnel.
+    fn command(&mut self, command: &str) -> io::Result<String> {
+        // only \r terminates the command, a \n after ATO would already be sent as data.
+        self.port.write_all(format!("{command}\r").as_bytes())?;
+        self.port.set_timeout(Duration::from_millis(50))?;
+        let start = SystemTime::now();
+        let mut response = Vec::new();
+        let mut buf = [0u8; 256];
+        while start.elapsed().unwrap() < Duration::from_secs(5) {
+            match self.port.read(&mut buf) {
+                Ok(len) => response.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+            if let Some(end) = final_result_end(&response) {
+                self.pending.extend_from_slice(&response[end..]);
+                response.truncate(end);
+                return Ok(String::from_utf8_lossy(&response).into_owned());
+            }
+        }
+        // without a result code, what arrived may well be data of the tunnel.
+        self.pending.extend_from_slice(&response);
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("modem did not answer {command}"),
+        ))
+    }
+}
+
+fn final_result_end(response: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    for (i, _) in response.windows(2).enumerate().filter(|x| x.1 == b"\r\n") {
+        let line = String::from_utf8_lossy(&response[start..i]);
+        let line = line.trim();
+        start = i + 2;
+        if line == "OK"
+            || line == "ERROR"
+            || line == "NO CARRIER"
+            || line.starts_with("CONNECT")
+            || line.starts_with("+CME ERROR")
+        {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// `first`, unless `then` failed, too. If both did, the error says so, so neither gets lost.
+fn both<T>(first: io::Result<T>, then: io::Result<()>) -> io::Result<T> {
+    match (first, then) {
+        (first, Ok(())) => first,
+        (Ok(_), Err(e)) => Err(e),
+        (Err(e), Err(then)) => Err(io::Error::new(e.kind(), format!("{e}, then {then}"))),
+    }
+}
+
+fn field<'a>(response: &'a str, prefix: &str, index: usize) -> Option<&'a str> {
+    response
+        .lines()
+        .find_map(|x| x.trim().strip_prefix(prefix))?
+        .split(',')
+        .nth(index)
+        .map(|x| x.trim().trim_matches('"'))
+}
+
+pub(crate) struct ModemStatus {
+    signal: Option<u8>,
+    registration: Option<u8>,
+    operator: Option<String>,
+}
+
+impl Display for ModemStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.signal {
+            Some(x @ 0..=31) => write!(f, "Signal {} dBm (CSQ {x}), ", -113 + 2 * x as i32)?,
+            _ => write!(f, "Signal unknown, ")?,
+        }
+        f.write_str(match self.registration {
+            Some(0) => "not registered",
+            Some(1) => "registered (home)",
+            Some(2) => "searching",
+            Some(3) => "registration denied",
+            Some(5) => "registered (roaming)",
+            _ => "registration unknown",
+        })?;
+        if let Some(ref operator) = self.operator {
+            write!(f, ", {operator}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_wait_times_are_rejected() {
+        for wait in ["x", "-1", "1.5", "99999999999999999999"] {
+            let text = format!("baud = 9600\n[init]\nAT\nWAIT {wait}\n");
+            let Err(e) = ModemProfile::parse(&text) else {
+                panic!("WAIT {wait} was accepted");
+            };
+            assert_eq!(e, format!("line 4: invalid wait time {wait}"));
+        }
+        let profile = ModemProfile::parse("[connect]\nWAIT  250 \nATD").unwrap();
+        assert!(matches!(
+            profile.connect[..],
+            [Step::Wait(x), Step::Command(ref y)] if x == Duration::from_millis(250) && y == "ATD"
+        ));
+    }
+}
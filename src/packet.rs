@@ -1,5 +1,29 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use enum_ordinalize::Ordinalize;
 
+/// Sent by the client and echoed by the server when connecting. The last byte is the protocol
+/// version, bump it whenever packets change.
+pub(crate) const HEADER: [u8; 4] = [b'R', b'P', b'F', 41];
+
+/// Options of a tunnel, sent as flags after the key. The server answers with the ones the client
+/// asked for and those it insists on, and both ends use these.
+///
+/// Append a CRC32 to every frame, so corrupted ones are dropped.
+pub(crate) const CHECKSUMS: u32 = 1;
+
+/// Acknowledge frames and send lost ones again, instead of resetting the streams. Implies
+/// `CHECKSUMS`.
+pub(crate) const RELIABLE: u32 = 2;
+
+/// Set in the ids of streams the client opened, so they never collide with the server's.
+pub(crate) const LOCAL_STREAM: u64 = 1 << 63;
+
 #[derive(Debug, PartialEq, Eq, Ordinalize)]
 pub(crate) enum PacketType {
     NewClient,
@@ -8,6 +32,243 @@ pub(crate) enum PacketType {
     ClientData,
     ServerData,
     ClientExceededBuffer,
-    Resync,
-    ResyncEcho,
+    Reset,
+    StreamRefused,
+    OpenPort,
+    PortOpened,
+    OpenStream,
+    StreamOpened,
+    ConnectStream,
+    ServiceStream,
+    ShutdownWrite,
+    LowLatency,
+    Ack,
+    Nack,
+    Ping,
+    Pong,
+}
+
+impl PacketType {
+    /// The length of the fields every packet of this type has at least. Frames that are shorter
+    /// can only be garbage.
+    pub fn min_len(&self) -> usize {
+        match self {
+            Self::KeepAlive | Self::Reset => 0,
+            Self::OpenPort => 2,
+            Self::PortOpened => 3,
+            Self::Ack | Self::Nack => 4,
+            Self::CloseClient
+            | Self::ClientData
+            | Self::ServerData
+            | Self::StreamOpened
+            | Self::ShutdownWrite
+            | Self::LowLatency
+            | Self::Ping
+            | Self::Pong => 8,
+            Self::StreamRefused => 9,
+            // the id, then at least the kind of the addresses and the length of a name.
+            Self::NewClient => 9,
+            Self::ServiceStream => 10,
+            Self::OpenStream => 11,
+            Self::ConnectStream => 12,
+            Self::ClientExceededBuffer => 24,
+        }
+    }
+}
+
+/// A packet to be sent in a frame: its type, followed by its fields.
+pub(crate) struct Packet(pub(crate) Vec<u8>);
+
+impl Packet {
+    pub fn new(kind: PacketType) -> Self {
+        Self(vec![kind.ordinal() as u8])
+    }
+
+    pub fn with(mut self, data: &[u8]) -> Self {
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Adds the addresses of a new stream: a 0 if they are unknown (e.g. on a unix socket),
+    /// otherwise 4 or 6, the peer's and local ip and then both ports.
+    pub fn with_addresses(self, addresses: Option<Addresses>) -> Self {
+        let Some(Addresses { peer, local }) = addresses else {
+            return self.with(&[0]);
+        };
+        let packet = match (peer.ip(), local.ip()) {
+            (IpAddr::V4(peer), IpAddr::V4(local)) => {
+                self.with(&[4]).with(&peer.octets()).with(&local.octets())
+            }
+            (peer, local) => {
+                let v6 = |x: IpAddr| match x {
+                    IpAddr::V4(x) => x.to_ipv6_mapped(),
+                    IpAddr::V6(x) => x,
+                };
+                self.with(&[6])
+                    .with(&v6(peer).octets())
+                    .with(&v6(local).octets())
+            }
+        };
+        packet
+            .with(&peer.port().to_be_bytes())
+            .with(&local.port().to_be_bytes())
+    }
+
+    /// Adds where a stream should be connected to: the port, then the host prefixed with its
+    /// length. Follows the id in `OpenStream` and the addresses in `ConnectStream`.
+    pub fn with_target(self, host: &str, port: u16) -> Self {
+        self.with(&port.to_be_bytes()).with_name(host)
+    }
+
+    /// Adds a host or service name, prefixed with its length.
+    pub fn with_name(self, name: &str) -> Self {
+        self.with(&[name.len() as u8]).with(name.as_bytes())
+    }
+
+    /// A Ping with the current time in microseconds. The Pong echoes it, so the round-trip time
+    /// is measured on one clock.
+    pub fn ping() -> Self {
+        Self::new(PacketType::Ping).with(&(now_micros() as u64).to_be_bytes())
+    }
+}
+
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+/// The round-trip time of a Pong, from the time it echoes.
+pub(crate) fn round_trip(pong: &[u8]) -> Duration {
+    let sent = u64::from_be_bytes(pong[..8].try_into().unwrap());
+    Duration::from_micros((now_micros() as u64).saturating_sub(sent))
+}
+
+/// Why the client couldn't connect a new stream to the destination, sent with `StreamRefused`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ordinalize)]
+pub(crate) enum RefuseReason {
+    Other,
+    ConnectionRefused,
+    TimedOut,
+    NotFound,
+    PermissionDenied,
+    NotAllowed,
+}
+
+impl From<ErrorKind> for RefuseReason {
+    fn from(value: ErrorKind) -> Self {
+        match value {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::TimedOut => Self::TimedOut,
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Display for RefuseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Other => "connection failed",
+            Self::ConnectionRefused => "connection refused",
+            Self::TimedOut => "timed out",
+            Self::NotFound => "destination not found",
+            Self::PermissionDenied => "permission denied",
+            Self::NotAllowed => "destination not allowed",
+        })
+    }
+}
+
+/// The answer to `OpenPort`, sent with `PortOpened` and the port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ordinalize)]
+pub(crate) enum PortStatus {
+    Opened,
+    NotAllowed,
+    InUse,
+}
+
+impl Display for PortStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Opened => "opened",
+            Self::NotAllowed => "not allowed for this key",
+            Self::InUse => "already in use",
+        })
+    }
+}
+
+/// The two ends of a public connection: the peer and the address it connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Addresses {
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+}
+
+impl Addresses {
+    pub fn new(peer: SocketAddr, local: SocketAddr) -> Self {
+        let canonical = |x: SocketAddr| SocketAddr::new(x.ip().to_canonical(), x.port());
+        let (peer, local) = (canonical(peer), canonical(local));
+        if peer.is_ipv4() == local.is_ipv4() {
+            return Self { peer, local };
+        }
+        // both ends have to be in the same family when sent.
+        let v6 = |x: SocketAddr| match x.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), x.port()),
+            IpAddr::V6(_) => x,
+        };
+        Self {
+            peer: v6(peer),
+            local: v6(local),
+        }
+    }
+}
+
+pub(crate) fn read_addresses<T: Read>(tcp: &mut T) -> io::Result<Option<Addresses>> {
+    let mut kind = [0u8; 1];
+    tcp.read_exact(&mut kind)?;
+    let (peer, local) = match kind[0] {
+        0 => return Ok(None),
+        4 => {
+            let mut buf = [0u8; 8];
+            tcp.read_exact(&mut buf)?;
+            let ip = |x: &[u8]| IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(x).unwrap()));
+            (ip(&buf[..4]), ip(&buf[4..]))
+        }
+        6 => {
+            let mut buf = [0u8; 32];
+            tcp.read_exact(&mut buf)?;
+            let ip = |x: &[u8]| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(x).unwrap()));
+            (ip(&buf[..16]), ip(&buf[16..]))
+        }
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "bad address kind")),
+    };
+    let mut ports = [0u8; 4];
+    tcp.read_exact(&mut ports)?;
+    Ok(Some(Addresses {
+        peer: SocketAddr::new(peer, u16::from_be_bytes([ports[0], ports[1]])),
+        local: SocketAddr::new(local, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+pub(crate) fn read_target<T: Read>(tcp: &mut T) -> io::Result<(String, u16)> {
+    let mut port = [0u8; 2];
+    tcp.read_exact(&mut port)?;
+    Ok((read_name(tcp)?, u16::from_be_bytes(port)))
+}
+
+pub(crate) fn read_name<T: Read>(tcp: &mut T) -> io::Result<String> {
+    let mut len = [0u8; 1];
+    tcp.read_exact(&mut len)?;
+    let mut name = vec![0u8; len[0] as usize];
+    tcp.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|_| io::Error::new(ErrorKind::InvalidData, "bad name"))
+}
+
+/// Hosts and service names are sent with a one-byte length, so longer ones can't be used.
+pub(crate) fn check_name(name: &str) {
+    if name.len() > 255 {
+        panic!("{name} is too long, names may have at most 255 bytes.");
+    }
 }
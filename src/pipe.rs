@@ -0,0 +1,133 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use crate::DEFAULT_TIMEOUT;
+
+/// A pair of pipes acting like a socket: our own stdin/stdout, or those of a child process.
+pub(crate) struct Pipe {
+    input: File,
+    output: File,
+    /// The file status flags of `input` and `output` before they were made nonblocking. The dup'd
+    /// stdio shares them with the parent, so they are restored on drop.
+    flags: [libc::c_int; 2],
+    nonblocking: bool,
+    timeout: Duration,
+    child: Option<Child>,
+}
+
+impl Pipe {
+    pub fn stdio() -> io::Result<Self> {
+        // dup'd so that dropping the pipe doesn't close the real stdio.
+        let (input, output) = unsafe { (libc::dup(0), libc::dup(1)) };
+        if input < 0 || output < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { Self::new(File::from_raw_fd(input), File::from_raw_fd(output), None) }
+    }
+
+    /// Runs `command` through the shell and talks to it through its stdin and stdout.
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = OwnedFd::from(child.stdout.take().unwrap());
+        let output = OwnedFd::from(child.stdin.take().unwrap());
+        Self::new(File::from(input), File::from(output), Some(child))
+    }
+
+    fn new(input: File, output: File, child: Option<Child>) -> io::Result<Self> {
+        // blocking is emulated using poll, so the timeout can be honored.
+        let mut flags = [0; 2];
+        for (fd, flags) in [input.as_raw_fd(), output.as_raw_fd()]
+            .into_iter()
+            .zip(&mut flags)
+        {
+            *flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if *flags < 0
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, *flags | libc::O_NONBLOCK) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Self {
+            input,
+            output,
+            flags,
+            nonblocking: false,
+            timeout: DEFAULT_TIMEOUT,
+            child,
+        })
+    }
+
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn wait(&self, fd: i32, events: i16) -> io::Result<()> {
+        if self.nonblocking {
+            return Ok(());
+        }
+        let mut pollfd = libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, self.timeout.as_millis() as i32) } {
+            0 => Err(io::Error::new(ErrorKind::TimedOut, "timed out")),
+            x if x < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The fd that is read from.
+impl AsRawFd for Pipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.input.as_raw_fd()
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait(self.input.as_raw_fd(), libc::POLLIN)?;
+        self.input.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wait(self.output.as_raw_fd(), libc::POLLOUT)?;
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        for (fd, flags) in [self.input.as_raw_fd(), self.output.as_raw_fd()]
+            .into_iter()
+            .zip(self.flags)
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, flags) };
+        }
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
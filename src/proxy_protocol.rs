@@ -0,0 +1,121 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::Addresses;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A HAProxy PROXY protocol version, to tell the destination who the public peer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" | "v1" => Ok(Self::V1),
+            "2" | "v2" => Ok(Self::V2),
+            _ => Err(format!("unknown PROXY protocol version {s}")),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    /// The header to write to the destination before any data.
+    pub(crate) fn header(self, addresses: Option<Addresses>) -> Vec<u8> {
+        match self {
+            Self::V1 => Self::v1(addresses).into_bytes(),
+            Self::V2 => Self::v2(addresses),
+        }
+    }
+
+    fn v1(addresses: Option<Addresses>) -> String {
+        let Some(Addresses { peer, local }) = addresses else {
+            return "PROXY UNKNOWN\r\n".to_owned();
+        };
+        let family = if peer.is_ipv4() { "TCP4" } else { "TCP6" };
+        format!(
+            "PROXY {family} {} {} {} {}\r\n",
+            peer.ip(),
+            local.ip(),
+            peer.port(),
+            local.port()
+        )
+    }
+
+    fn v2(addresses: Option<Addresses>) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        let Some(Addresses { peer, local }) = addresses else {
+            // LOCAL command: the destination uses the connection's own addresses.
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            return header;
+        };
+        let mut body = Vec::new();
+        let family = match (peer.ip(), local.ip()) {
+            (IpAddr::V4(peer), IpAddr::V4(local)) => {
+                body.extend_from_slice(&peer.octets());
+                body.extend_from_slice(&local.octets());
+                0x11
+            }
+            (IpAddr::V6(peer), IpAddr::V6(local)) => {
+                body.extend_from_slice(&peer.octets());
+                body.extend_from_slice(&local.octets());
+                0x21
+            }
+            _ => unreachable!("addresses are always of the same family"),
+        };
+        body.extend_from_slice(&peer.port().to_be_bytes());
+        body.extend_from_slice(&local.port().to_be_bytes());
+        header.extend_from_slice(&[0x21, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(peer: &str, local: &str) -> Option<Addresses> {
+        Some(Addresses::new(
+            peer.parse().unwrap(),
+            local.parse().unwrap(),
+        ))
+    }
+
+    #[test]
+    fn v1_headers() {
+        let header = ProxyProtocol::V1.header(addresses("192.0.2.1:5000", "[::ffff:10.0.0.1]:80"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 10.0.0.1 5000 80\r\n");
+        let header = ProxyProtocol::V1.header(addresses("192.0.2.1:5000", "[2001:db8::1]:80"));
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::1 5000 80\r\n"
+        );
+        assert_eq!(ProxyProtocol::V1.header(None), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_headers() {
+        let header = ProxyProtocol::V2.header(addresses("192.0.2.1:5000", "10.0.0.1:80"));
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 10, 0, 0, 1]);
+        expected.extend_from_slice(&[0x13, 0x88, 0, 80]);
+        assert_eq!(header, expected);
+
+        let header = ProxyProtocol::V2.header(addresses("[2001:db8::2]:5000", "[2001:db8::1]:80"));
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(header[16 + 15], 2);
+        assert_eq!(header[32 + 15], 1);
+        assert_eq!(&header[48..], &[0x13, 0x88, 0, 80]);
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0, 0, 0]);
+        assert_eq!(ProxyProtocol::V2.header(None), expected);
+    }
+}
@@ -1,102 +1,521 @@
 use std::{
     collections::HashMap,
-    io::Read,
-    io::Write,
-    net::{Shutdown, TcpListener},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
     vec,
 };
 
-use crate::{Connection, PacketType, SocketAdapter};
-
-fn resync(tcp: &mut SocketAdapter) {
-    println!();
-    eprintln!("Client version mismatch or broken connection. Re-syncing in case of the latter...");
-    tcp.internal.set_print(false);
-    tcp.write_now().unwrap();
-    tcp.write(&[PacketType::Resync.ordinal() as u8]).unwrap();
-    tcp.write_now().unwrap();
-    eprintln!(
-        "Sent resync packet. Client should now wait 8 seconds and then send a resync packet back, initiating a normal re-sync."
-    );
-    let mut buf = [0; 4096];
-    // read all packets that are still pending.
-    while let Some(Some(_x @ 1..)) = tcp.poll(&mut buf).ok() {}
-    // wait 5 seconds
-    thread::sleep(Duration::from_secs(5));
-    // read all packets that are still pending.
-    while let Some(Some(_x @ 1..)) = tcp.poll(&mut buf).ok() {}
-    // client should now have stopped sending packets.
-}
-
-pub fn server(port: u16, key: &str, sleep_delay_ms: u64) {
-    let mut buf1 = [0u8; 1];
-    let mut buf4 = [0u8; 4];
-    let mut buf8 = [0u8; 8];
-    let mut buf16 = [0u8; 16];
-    let mut buf = [0; 1024];
-    let tcpl = TcpListener::bind(("::0", port)).unwrap();
-    let mut tcp = loop {
-        let Ok(mut tcp) = tcpl.accept() else { continue };
-        let Ok(()) = tcp.0.read_exact(&mut buf4) else {
-            tcp.0.shutdown(Shutdown::Both).unwrap();
-            continue;
+#[cfg(unix)]
+use crate::Pipe;
+use crate::{
+    check_name, read_target, round_trip, socks, target_allowed, vhost, wait_readable, Addresses,
+    ArqTimers, Cidr, Connection, Framed, Listener, Packet, PacketType, PortStatus, Received,
+    RefuseReason, SocketAdapter, StreamInfo, TokenBucket, CHECKSUMS, HEADER, LOCAL_STREAM,
+    RELIABLE,
+};
+
+/// The most SOCKS5, HTTP and TLS peers in the middle of their handshake at once. Each has a
+/// thread, so further ones are turned away until some are done.
+const MAX_HANDSHAKES: usize = 256;
+
+/// The most clients in the middle of authenticating at once. Each has a thread, so further ones
+/// are turned away until some are done.
+const MAX_AUTHENTICATING: usize = 64;
+
+/// The most streams a client may have opened that are still being connected. Each has a thread,
+/// so further ones are refused until some are done.
+const MAX_CONNECTING: usize = 64;
+
+/// How new public connections are spread over the connected clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastStreams,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-streams" => Ok(Self::LeastStreams),
+            _ => Err(format!("unknown balancing strategy {s}")),
+        }
+    }
+}
+
+/// Another key with its own public ports. Clients using it only get connections from these.
+pub struct Tenant<'a> {
+    pub key: &'a str,
+    pub ports: Vec<u16>,
+    /// Ports its clients may open themselves.
+    pub port_ranges: Vec<RangeInclusive<u16>>,
+}
+
+pub struct ServerParams<'a> {
+    pub port: u16,
+    pub key: &'a str,
+    pub sleep_delay_ms: u64,
+    /// Use stdin/stdout as the connection to the client, e.g. when started through ssh.
+    pub stdio: bool,
+    /// Accept public connections on this port instead of `port`. Any number of clients can then
+    /// connect on `port`.
+    pub public_port: Option<u16>,
+    /// How new public connections are spread when more than one client is connected.
+    pub balance: Balance,
+    /// Whether public connections are accepted on the public port.
+    pub public_tcp: bool,
+    /// Also accept public connections on this unix socket.
+    pub unix_socket: Option<&'a str>,
+    pub unix_socket_mode: Option<u32>,
+    /// If not empty, only public connections from these networks are accepted.
+    pub allow: Vec<Cidr>,
+    /// Public connections from these networks are always rejected.
+    pub deny: Vec<Cidr>,
+    /// Maximum number of concurrent public connections of each tenant, 0 for no limit.
+    pub max_streams: usize,
+    /// Maximum number of concurrent public connections of all tenants together, 0 for no limit.
+    pub max_streams_total: usize,
+    /// Maximum number of concurrent public connections per ip, 0 for no limit.
+    pub max_streams_per_ip: usize,
+    /// New public connections per second and ip, 0 for no limit.
+    pub accept_rate: f64,
+    /// How many connections an ip may open at once before `accept_rate` applies.
+    pub accept_burst: f64,
+    /// Bytes per second sent to the clients in total, 0 for no limit.
+    pub download_limit: u64,
+    /// Bytes per second sent to the client for each connection, 0 for no limit.
+    pub download_limit_per_stream: u64,
+    /// Ports clients using `key` may open themselves.
+    pub port_ranges: Vec<RangeInclusive<u16>>,
+    /// More keys with their own public ports, served next to `key` by the same process.
+    pub tenants: Vec<Tenant<'a>>,
+    /// Where clients may open streams to from the server, as host:port. Either may be `*`.
+    pub allow_forward: Vec<&'a str>,
+    /// Accept SOCKS5 connections on this port. Clients of `key` connect them to the requested
+    /// destination if they allow it.
+    pub socks_port: Option<u16>,
+    /// Users and passwords SOCKS5 peers have to log in with. If empty, no login is needed.
+    pub socks_users: Vec<(&'a str, &'a str)>,
+    /// Accept HTTP connections on this port and route them by their `Host:` header, using
+    /// `vhosts`.
+    pub http_port: Option<u16>,
+    /// Hosts and the services of the clients of `key` they are routed to. Hosts may start with
+    /// `*.`.
+    pub vhosts: Vec<(&'a str, &'a str)>,
+    /// The status requests for unknown hosts get, usually 404 or 421.
+    pub http_fallback_status: u16,
+    /// Accept TLS connections on this port and route them by the server name in their
+    /// ClientHello, using `vhosts`. They are forwarded as they are, without being decrypted.
+    pub tls_port: Option<u16>,
+    /// Check every frame with a CRC32, even for clients that don't ask for it.
+    pub checksums: bool,
+    /// Acknowledge frames and send lost ones again, even for clients that don't ask for it.
+    pub reliable: bool,
+    /// How often clients are pinged.
+    pub ping_interval_ms: u64,
+    /// Clients that weren't heard from for this long are dropped.
+    pub peer_timeout_ms: u64,
+    /// How long a read or write on a client's connection may block, e.g. for the rest of a
+    /// frame.
+    pub io_timeout_ms: u64,
+    /// How long a read or write on a forwarded connection may block.
+    pub stream_timeout_ms: u64,
+    /// How long SOCKS5, HTTP and TLS peers get to say where they want to go.
+    pub handshake_timeout_ms: u64,
+    /// On reliable tunnels: how long a received frame may wait for its acknowledgement, how
+    /// often missing frames are asked for, and how long the oldest unacknowledged frame waits
+    /// before it is sent again, until a round-trip time was measured.
+    pub ack_delay_ms: u64,
+    pub nack_interval_ms: u64,
+    pub retransmit_timeout_ms: u64,
+}
+
+struct Stream {
+    socket: SocketAdapter,
+    addresses: Option<Addresses>,
+    /// Whether this is a SOCKS5 connection still waiting for the client to connect it.
+    socks: bool,
+    /// Whether this is an HTTP connection, which gets an error response if the client can't
+    /// connect it.
+    http: bool,
+}
+
+/// A stream opened by the client and the outcome of connecting it.
+type Connected = (u64, io::Result<TcpStream>);
+
+/// An authenticated client and the public connections forwarded to it.
+struct Tunnel {
+    tcp: Framed,
+    name: String,
+    /// The index of the key the client used, 0 for `ServerParams::key`.
+    tenant: usize,
+    streams: HashMap<u64, Stream>,
+    /// Ports the client opened, closed again when it is dropped.
+    listeners: Vec<Listener>,
+    /// Streams the client opened get connected on their own thread and are reported here.
+    connecting: (Sender<Connected>, Receiver<Connected>),
+    /// How many of these are still being connected.
+    connecting_count: usize,
+    id: u64,
+    last_ping: SystemTime,
+    /// When the client was last heard from.
+    last_keep_alive: SystemTime,
+}
+
+/// Where the client connects a public connection to.
+enum Target {
+    /// Its destination.
+    Destination,
+    /// A host and port a SOCKS5 peer asked for.
+    Socks(String, u16),
+    /// One of its services, chosen by the host the peer asked for. `head` is what was read from
+    /// the peer to find out, and `http` whether it was an HTTP request rather than a TLS
+    /// ClientHello.
+    Service {
+        name: String,
+        head: Vec<u8>,
+        http: bool,
+    },
+}
+
+/// Where connections from a listener go.
+#[derive(Clone, Copy)]
+enum Route {
+    Tenant(usize),
+    Tunnel(usize),
+}
+
+impl<'a> ServerParams<'a> {
+    /// What `server(port, key, sleep_delay_ms)` did before it took `ServerParams`: one client on
+    /// `port`, with everything else off and the default timeouts.
+    pub fn new(port: u16, key: &'a str, sleep_delay_ms: u64) -> Self {
+        Self {
+            port,
+            key,
+            sleep_delay_ms,
+            stdio: false,
+            public_port: None,
+            balance: Balance::RoundRobin,
+            public_tcp: true,
+            unix_socket: None,
+            unix_socket_mode: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_streams: 0,
+            max_streams_total: 0,
+            max_streams_per_ip: 0,
+            accept_rate: 0.0,
+            accept_burst: 1.0,
+            download_limit: 0,
+            download_limit_per_stream: 0,
+            port_ranges: Vec::new(),
+            tenants: Vec::new(),
+            allow_forward: Vec::new(),
+            socks_port: None,
+            socks_users: Vec::new(),
+            http_port: None,
+            vhosts: Vec::new(),
+            http_fallback_status: 404,
+            tls_port: None,
+            checksums: false,
+            reliable: false,
+            ping_interval_ms: 10000,
+            peer_timeout_ms: 60000,
+            io_timeout_ms: 20000,
+            stream_timeout_ms: 20000,
+            handshake_timeout_ms: 20000,
+            ack_delay_ms: 200,
+            nack_interval_ms: 2000,
+            retransmit_timeout_ms: 3000,
+        }
+    }
+
+    fn port_ranges(&self, tenant: usize) -> &[RangeInclusive<u16>] {
+        match tenant {
+            0 => &self.port_ranges,
+            x => &self.tenants[x - 1].port_ranges,
+        }
+    }
+
+    /// Checks the limits for a new connection to `tenant`. Apart from `max_streams_total`, only its
+    /// own connections count, so one tenant can't use up the connections of another. Peers still in their handshake count
+    /// against the limit per ip, too. Without `accept_buckets`, the accept rate isn't checked.
+    fn admit(
+        &self,
+        tenant: usize,
+        peer: Option<SocketAddr>,
+        tunnels: &[Tunnel],
+        handshaking: &HashMap<IpAddr, usize>,
+        accept_buckets: Option<&mut HashMap<IpAddr, TokenBucket>>,
+    ) -> Result<(), &'static str> {
+        let streams = || {
+            tunnels
+                .iter()
+                .filter(|x| x.tenant == tenant)
+                .flat_map(|x| x.streams.values())
         };
-        if buf4 == [b'R', b'P', b'F', 30] {
-            println!("Compatible client connected.");
-            if tcp.0.read_exact(&mut buf4).is_ok() && u32::from_be_bytes(buf4) == key.len() as u32 {
-                println!("Key length matches.");
-                let mut keybuf = vec![0u8; key.len()];
-                if tcp.0.read_exact(&mut keybuf).is_ok() && keybuf == key.as_bytes() {
-                    println!("Accepted.");
-                    break tcp.0;
+        if self.max_streams != 0 && streams().count() >= self.max_streams {
+            return Err("too many connections");
+        }
+        if self.max_streams_total != 0
+            && tunnels.iter().map(|x| x.streams.len()).sum::<usize>() >= self.max_streams_total
+        {
+            return Err("too many connections");
+        }
+        // peers on the unix socket are local.
+        let Some(ip) = peer.map(|x| x.ip()) else {
+            return Ok(());
+        };
+        if self.deny.iter().any(|x| x.contains(ip))
+            || !(self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip)))
+        {
+            return Err("address not allowed");
+        }
+        if self.max_streams_per_ip != 0
+            && streams()
+                .filter(|x| x.addresses.is_some_and(|x| x.peer.ip() == ip))
+                .count()
+                + handshaking.get(&ip).copied().unwrap_or(0)
+                >= self.max_streams_per_ip
+        {
+            return Err("too many connections from this address");
+        }
+        if let Some(accept_buckets) = accept_buckets.filter(|_| self.accept_rate > 0.0) {
+            accept_buckets.retain(|_, x| !x.is_full());
+            let bucket = accept_buckets
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(self.accept_rate, self.accept_burst.max(1.0)));
+            if !bucket.take(1.0) {
+                return Err("connecting too fast");
+            }
+        }
+        Ok(())
+    }
+
+    fn pick(&self, tunnels: &[Tunnel], tenant: usize, next_tunnel: &mut usize) -> Option<usize> {
+        let mut candidates = (0..tunnels.len()).filter(|&i| tunnels[i].tenant == tenant);
+        match self.balance {
+            Balance::RoundRobin => {
+                let count = candidates.clone().count();
+                if count == 0 {
+                    return None;
                 }
-                println!("Key content does not match.");
+                candidates.nth((*next_tunnel, *next_tunnel += 1).0 % count)
             }
-            println!("Key mismatch - forgetting client.");
+            Balance::LeastStreams => candidates.min_by_key(|&i| tunnels[i].streams.len()),
         }
-    };
+    }
+}
+
+impl Tunnel {
+    fn new(
+        mut tcp: Connection,
+        name: String,
+        tenant: usize,
+        flags: u32,
+        params: &ServerParams,
+    ) -> Self {
+        // a connection that is already gone fails on its next read anyway.
+        let _ = tcp.set_timeout(Duration::from_millis(params.io_timeout_ms));
+        let timers = ArqTimers {
+            ack_delay: Duration::from_millis(params.ack_delay_ms),
+            nack_interval: Duration::from_millis(params.nack_interval_ms),
+            retransmit_timeout: Duration::from_millis(params.retransmit_timeout_ms),
+        };
+        Self {
+            tcp: Framed::new(SocketAdapter::new(tcp), flags, timers),
+            name,
+            tenant,
+            streams: HashMap::new(),
+            listeners: Vec::new(),
+            connecting: mpsc::channel(),
+            connecting_count: 0,
+            id: 0,
+            last_ping: SystemTime::now(),
+            last_keep_alive: SystemTime::now(),
+        }
+    }
+
+    fn open(
+        &mut self,
+        socket: Connection,
+        addresses: Option<Addresses>,
+        target: Target,
+        params: &ServerParams,
+    ) -> io::Result<()> {
+        let mut socket = SocketAdapter::new(socket);
+        socket.set_read_limit(params.download_limit_per_stream);
+        let _ = socket
+            .internal
+            .set_timeout(Duration::from_millis(params.stream_timeout_ms));
+        let info = StreamInfo::new(self.id, addresses);
+        eprintln!();
+        match target {
+            Target::Destination => eprintln!("New connection {info} for client {}.", self.name),
+            Target::Socks(ref host, port) => eprintln!(
+                "New connection {info} to {host}:{port} for client {}.",
+                self.name
+            ),
+            Target::Service { ref name, .. } => eprintln!(
+                "New connection {info} to service {name} for client {}.",
+                self.name
+            ),
+        }
+        let stream = Stream {
+            socket,
+            addresses,
+            socks: matches!(target, Target::Socks(..)),
+            http: matches!(target, Target::Service { http: true, .. }),
+        };
+        let id = (self.id, self.id += 1).0;
+        self.streams.insert(id, stream);
+        let id_bytes = id.to_be_bytes();
+        let packet = match target {
+            Target::Destination => Packet::new(PacketType::NewClient)
+                .with(&id_bytes)
+                .with_addresses(addresses),
+            Target::Socks(ref host, port) => Packet::new(PacketType::ConnectStream)
+                .with(&id_bytes)
+                .with_addresses(addresses)
+                .with_target(host, port),
+            Target::Service { ref name, .. } => Packet::new(PacketType::ServiceStream)
+                .with(&id_bytes)
+                .with_addresses(addresses)
+                .with_name(name),
+        };
+        self.tcp.send(packet)?;
+        if let Target::Service { head, .. } = target {
+            for chunk in head.chunks(1024) {
+                let packet = Packet::new(PacketType::ClientData).with(&id_bytes);
+                self.tcp.send(packet.with(chunk))?;
+            }
+        }
+        Ok(())
+    }
 
-    tcp.write_all(&[b'R', b'P', b'F', 30]).unwrap();
+    /// Aborts all streams, as any of them may have lost data.
+    fn drop_streams(&mut self) {
+        for (_, x) in self.streams.drain() {
+            let _ = x.socket.internal.abort();
+        }
+    }
 
-    tcpl.set_nonblocking(true).unwrap();
+    /// Drops all streams after frames from the client were lost, and has the client do the
+    /// same. The tunnel itself stays up.
+    fn reset(&mut self, reason: &str) -> io::Result<()> {
+        eprintln!();
+        eprintln!(
+            "Lost sync with client {} ({reason}), resetting its connections.",
+            self.name
+        );
+        self.drop_streams();
+        self.tcp.send(Packet::new(PacketType::Reset))
+    }
 
-    let mut tcp = SocketAdapter::new(Connection::new_tcp(tcp, true));
-    let mut sockets: HashMap<u64, SocketAdapter> = HashMap::new();
-    let mut id = 0;
-    let mut last_keep_alive_sent = SystemTime::now();
-    let mut last_keep_alive = SystemTime::now();
-    loop {
+    /// Forwards what the public connections sent and handles one packet from the client.
+    /// Returns whether anything happened.
+    fn step(
+        &mut self,
+        params: &ServerParams,
+        download_limit: &mut Option<TokenBucket>,
+        streams_left: &mut usize,
+    ) -> io::Result<bool> {
+        let mut buf1 = [0u8; 1];
+        let mut buf2 = [0u8; 2];
+        let mut buf8 = [0u8; 8];
+        let mut buf16 = [0u8; 16];
+        let mut buf = [0; 1024];
         let mut did_anything = false;
+        let tcp = &mut self.tcp;
 
-        if last_keep_alive_sent.elapsed().unwrap().as_secs() >= 10 {
-            last_keep_alive_sent = SystemTime::now();
-            tcp.write(&[PacketType::KeepAlive.ordinal() as u8]).unwrap();
+        if self.last_ping.elapsed().unwrap().as_millis() >= params.ping_interval_ms as u128 {
+            self.last_ping = SystemTime::now();
+            tcp.send(Packet::ping())?;
         }
-        if last_keep_alive.elapsed().unwrap().as_secs() >= 60 {
-            panic!("connection dropped. exiting.");
+        if self.last_keep_alive.elapsed().unwrap().as_millis() >= params.peer_timeout_ms as u128 {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("no keepalive for {}ms", params.peer_timeout_ms),
+            ));
         }
 
-        if let Ok(new) = tcpl.accept() {
-            let new = SocketAdapter::new(Connection::new_tcp(new.0, false));
-            sockets.insert((id, id += 1).0, new);
-            tcp.write(&[PacketType::NewClient.ordinal() as u8]).unwrap();
+        for (i, result) in self.connecting.1.try_iter() {
             did_anything = true;
+            self.connecting_count -= 1;
+            match result {
+                Ok(stream) => {
+                    let mut socket = SocketAdapter::new(Connection::new_tcp(stream, false));
+                    socket.set_read_limit(params.download_limit_per_stream);
+                    let _ = socket
+                        .internal
+                        .set_timeout(Duration::from_millis(params.stream_timeout_ms));
+                    let stream = Stream {
+                        socket,
+                        addresses: None,
+                        socks: false,
+                        http: false,
+                    };
+                    self.streams.insert(i, stream);
+                    *streams_left += 1;
+                    tcp.send(Packet::new(PacketType::StreamOpened).with(&i.to_be_bytes()))?;
+                }
+                Err(e) => {
+                    eprintln!();
+                    eprintln!(
+                        "Unable to forward local connection #{} of client {}: {e}",
+                        i & !LOCAL_STREAM,
+                        self.name
+                    );
+                    let reason = RefuseReason::from(e.kind());
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&i.to_be_bytes())
+                            .with(&[reason.ordinal() as u8]),
+                    )?;
+                }
+            }
         }
 
         let mut to_remove = vec![];
-        for (&i, socket) in sockets.iter_mut() {
-            if let Ok(x) = socket.poll(&mut buf) {
+        let mut finished = vec![];
+        // low-latency streams are read first, so they get their share of the download limit.
+        let mut order: Vec<u64> = self.streams.keys().copied().collect();
+        order.sort_by_key(|i| !self.streams[i].socket.is_low_latency());
+        for i in order {
+            let socket = &mut self.streams.get_mut(&i).unwrap().socket;
+            let max = match download_limit {
+                Some(ref mut limit) => limit.share(*streams_left).min(buf.len()),
+                None => buf.len(),
+            };
+            *streams_left -= 1;
+            if let Ok(x) = socket.poll(&mut buf[..max]) {
                 if let Some(len) = x {
+                    if let Some(ref mut limit) = download_limit {
+                        limit.consume(len as f64);
+                    }
                     if len == 0 {
-                        to_remove.push(i);
+                        // the client may still send, only this direction is done.
+                        tcp.send(Packet::new(PacketType::ShutdownWrite).with(&i.to_be_bytes()))?;
+                        if socket.is_finished() {
+                            finished.push(i);
+                        }
                     } else {
-                        tcp.write(&[PacketType::ClientData.ordinal() as u8])
-                            .unwrap();
-                        tcp.write(&i.to_be_bytes()).unwrap();
-                        tcp.write(&(len as u32).to_be_bytes()).unwrap();
-                        tcp.write(&buf[..len]).unwrap();
+                        tcp.send(
+                            Packet::new(PacketType::ClientData)
+                                .with(&i.to_be_bytes())
+                                .with(&buf[..len]),
+                        )?;
                     }
                     did_anything = true;
                 }
@@ -105,94 +524,736 @@ pub fn server(port: u16, key: &str, sleep_delay_ms: u64) {
                 did_anything = true;
             }
             if let x @ 1.. = socket.clear_delay() {
-                tcp.write(&[PacketType::ClientExceededBuffer.ordinal() as u8])
-                    .unwrap();
-                tcp.write(&i.to_be_bytes()).unwrap();
-                tcp.write(&x.to_be_bytes()).unwrap();
+                tcp.send(
+                    Packet::new(PacketType::ClientExceededBuffer)
+                        .with(&i.to_be_bytes())
+                        .with(&x.to_be_bytes()),
+                )?;
                 socket.punish(x);
             }
         }
         for i in to_remove.into_iter().rev() {
-            tcp.write(&[PacketType::CloseClient.ordinal() as u8])
-                .unwrap();
-            tcp.write(&i.to_be_bytes()).unwrap();
-            if let Some(x) = sockets.remove(&i) {
-                let _ = x.internal.close();
+            tcp.send(Packet::new(PacketType::CloseClient).with(&i.to_be_bytes()))?;
+            if let Some(x) = self.streams.remove(&i) {
+                let _ = x.socket.internal.close();
             }
         }
-
-        tcp.update().unwrap();
-        if tcp.poll_exact(&mut buf1).unwrap().is_none() {
-            if !did_anything {
-                thread::sleep(Duration::from_millis(sleep_delay_ms));
+        for i in finished {
+            if let Some(x) = self.streams.remove(&i) {
+                let _ = x.socket.internal.close();
             }
-            continue;
         }
 
-        let Some(pt) = PacketType::from_ordinal(buf1[0] as i8) else {
-            resync(&mut tcp);
-            continue;
+        tcp.socket.update()?;
+        let (pt, data) = match tcp.poll()? {
+            None => return Ok(did_anything),
+            Some(Received::Packet(pt, data)) => (pt, data),
+            Some(Received::Lost(reason)) => {
+                self.reset(&reason)?;
+                return Ok(true);
+            }
         };
+        // the frame's length was checked, so the fixed fields can always be read.
+        let mut data = &data[..];
         match pt {
-            PacketType::NewClient => resync(&mut tcp),
-
             PacketType::CloseClient => {
-                tcp.read_now(&mut buf8).unwrap();
-                if let Some(x) = sockets.remove(&u64::from_be_bytes(buf8)) {
-                    let _ = x.internal.close();
+                data.read_exact(&mut buf8)?;
+                if let Some(x) = self.streams.remove(&u64::from_be_bytes(buf8)) {
+                    let _ = x.socket.internal.close();
+                }
+            }
+
+            PacketType::ShutdownWrite => {
+                data.read_exact(&mut buf8)?;
+                let idx = u64::from_be_bytes(buf8);
+                let result = self.streams.get_mut(&idx).map(|x| {
+                    let result = x.socket.shutdown_write();
+                    result.map(|()| x.socket.is_finished())
+                });
+                if let Some(Err(_)) = result {
+                    // the peer is gone, so the client's end has to be closed, too.
+                    tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))?;
+                }
+                if let Some(Err(_) | Ok(true)) = result {
+                    if let Some(x) = self.streams.remove(&idx) {
+                        let _ = x.socket.internal.close();
+                    }
+                }
+            }
+
+            PacketType::LowLatency => {
+                data.read_exact(&mut buf8)?;
+                if let Some(stream) = self.streams.get_mut(&u64::from_be_bytes(buf8)) {
+                    let _ = stream.socket.set_low_latency();
+                    // its frames shouldn't wait for more data on the way to the client either.
+                    tcp.socket.internal.set_nodelay(true)?;
                 }
             }
 
             PacketType::KeepAlive => {
-                last_keep_alive = SystemTime::now();
+                self.last_keep_alive = SystemTime::now();
             }
 
-            PacketType::ClientData => resync(&mut tcp),
+            PacketType::Ping => {
+                self.last_keep_alive = SystemTime::now();
+                tcp.send(Packet::new(PacketType::Pong).with(data))?;
+            }
 
-            PacketType::ServerData => {
-                tcp.read_now(&mut buf8).unwrap();
-                let idx = u64::from_be_bytes(buf8);
-                tcp.read_now(&mut buf4).unwrap();
-                let len = u32::from_be_bytes(buf4) as usize;
-                tcp.read_now(&mut buf[..len]).unwrap();
+            PacketType::Pong => {
+                self.last_keep_alive = SystemTime::now();
+                tcp.set_rtt(round_trip(data));
+            }
 
-                if let Some(socket) = sockets.get_mut(&idx) {
-                    let _ = socket.write_later(&buf[..len]);
+            PacketType::ServerData => {
+                data.read_exact(&mut buf8)?;
+                match self.streams.get_mut(&u64::from_be_bytes(buf8)) {
+                    Some(stream) => {
+                        let _ = stream.socket.write_later(data);
+                    }
+                    // e.g. a stream the client opened before it got our reset.
+                    None => tcp.send(Packet::new(PacketType::CloseClient).with(&buf8))?,
                 }
             }
 
             PacketType::ClientExceededBuffer => {
-                tcp.read_now(&mut buf8).unwrap();
+                data.read_exact(&mut buf8)?;
                 let idx = u64::from_be_bytes(buf8);
-                tcp.read_now(&mut buf16).unwrap();
+                data.read_exact(&mut buf16)?;
                 let amount = u128::from_be_bytes(buf16);
 
                 // a single connection doesn't need overuse-penalties
-                if let (true, Some(socket)) = (sockets.len() > 1, sockets.get_mut(&idx)) {
-                    socket.punish(amount);
+                if let (true, Some(stream)) = (self.streams.len() > 1, self.streams.get_mut(&idx)) {
+                    stream.socket.punish(amount);
                 }
             }
 
-            PacketType::Resync => {
-                println!();
-                tcp.internal.set_print(false);
+            PacketType::Reset => {
+                eprintln!();
+                eprintln!("Client {} lost sync, resetting its connections.", self.name);
+                self.drop_streams();
+            }
+
+            PacketType::StreamRefused => {
+                data.read_exact(&mut buf8)?;
+                let idx = u64::from_be_bytes(buf8);
+                data.read_exact(&mut buf1)?;
+                let reason =
+                    RefuseReason::from_ordinal(buf1[0] as i8).unwrap_or(RefuseReason::Other);
+                eprintln!();
                 eprintln!(
-                    "Client asked for a re-sync. Waiting 8 seconds, then sending resync-echo."
+                    "Client {} could not forward connection #{idx}: {reason}.",
+                    self.name
                 );
-                tcp.read_now(&mut buf8).unwrap();
-                id = u64::from_be_bytes(buf8).max(id);
-                tcp.write_now().unwrap();
-                thread::sleep(Duration::from_secs(8));
-                tcp.write(&[PacketType::ResyncEcho.ordinal() as u8])
-                    .unwrap();
-                tcp.write(&id.to_be_bytes()).unwrap();
-                tcp.write_now().unwrap();
-                eprintln!("Resync-Echo sent. Going back to normal operation.");
-                tcp.internal.set_print(true);
-            }
-
-            // this one can't happen, it should only come from the server
-            PacketType::ResyncEcho => resync(&mut tcp),
+                match self.streams.remove(&idx) {
+                    // SOCKS5 peers are told why before the connection is closed.
+                    Some(mut x) if x.socks => {
+                        let _ = x.socket.write_later(&socks::reply(Err(reason)));
+                        let _ = x.socket.write_now();
+                        let _ = x.socket.internal.close();
+                    }
+                    Some(mut x) if x.http => {
+                        let _ = x.socket.write_later(vhost::http_error(502).as_bytes());
+                        let _ = x.socket.write_now();
+                        let _ = x.socket.internal.close();
+                    }
+                    // the peer is reset right away instead of seeing a connection that closes
+                    // normally.
+                    Some(x) => {
+                        let _ = x.socket.internal.abort();
+                    }
+                    None => (),
+                }
+            }
+
+            PacketType::OpenPort => {
+                data.read_exact(&mut buf2)?;
+                let port = u16::from_be_bytes(buf2);
+                let (port, status) = match open_port(port, params.port_ranges(self.tenant)) {
+                    Ok(listener) => {
+                        let port = listener.local_addr()?.port();
+                        self.listeners.push(Listener::tcp(listener)?);
+                        eprintln!();
+                        eprintln!("Client {} opened public port {port}.", self.name);
+                        (port, PortStatus::Opened)
+                    }
+                    Err(status) => {
+                        eprintln!();
+                        eprintln!("Client {} can't open port {port}: {status}.", self.name);
+                        (port, status)
+                    }
+                };
+                tcp.send(
+                    Packet::new(PacketType::PortOpened)
+                        .with(&port.to_be_bytes())
+                        .with(&[status.ordinal() as u8]),
+                )?;
+            }
+
+            PacketType::OpenStream => {
+                data.read_exact(&mut buf8)?;
+                let idx = u64::from_be_bytes(buf8);
+                let Ok((host, port)) = read_target(&mut data) else {
+                    self.reset("malformed packet")?;
+                    return Ok(true);
+                };
+
+                eprintln!();
+                if self.connecting_count >= MAX_CONNECTING {
+                    eprintln!(
+                        "Client {} opens too many streams at once, refused {host}:{port}.",
+                        self.name
+                    );
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&buf8)
+                            .with(&[RefuseReason::Other.ordinal() as u8]),
+                    )?;
+                } else if target_allowed(&params.allow_forward, &host, port) {
+                    eprintln!(
+                        "Client {} forwards local connection #{} to {host}:{port}.",
+                        self.name,
+                        idx & !LOCAL_STREAM
+                    );
+                    let connected = self.connecting.0.clone();
+                    self.connecting_count += 1;
+                    thread::spawn(move || {
+                        let _ = connected.send((idx, TcpStream::connect((host.as_str(), port))));
+                    });
+                } else {
+                    eprintln!("Client {} may not forward to {host}:{port}.", self.name);
+                    tcp.send(
+                        Packet::new(PacketType::StreamRefused)
+                            .with(&buf8)
+                            .with(&[RefuseReason::NotAllowed.ordinal() as u8]),
+                    )?;
+                }
+            }
+
+            PacketType::StreamOpened => {
+                data.read_exact(&mut buf8)?;
+                let idx = u64::from_be_bytes(buf8);
+                if let Some(stream) = self.streams.get_mut(&idx).filter(|x| x.socks) {
+                    stream.socks = false;
+                    let _ = stream.socket.write_later(&socks::reply(Ok(())));
+                }
+            }
+
+            // handled by `Framed`
+            PacketType::Ack | PacketType::Nack => (),
+
+            // these can't happen, they should only come from the server
+            PacketType::NewClient
+            | PacketType::ClientData
+            | PacketType::PortOpened
+            | PacketType::ConnectStream
+            | PacketType::ServiceStream => self.reset("unexpected packet")?,
+        }
+        Ok(true)
+    }
+}
+
+/// Binds `port` for a client, or the first free port it may open if `port` is 0.
+fn open_port(port: u16, allowed: &[RangeInclusive<u16>]) -> Result<TcpListener, PortStatus> {
+    if allowed.is_empty() || port != 0 && !allowed.iter().any(|x| x.contains(&port)) {
+        return Err(PortStatus::NotAllowed);
+    }
+    if port != 0 {
+        return TcpListener::bind(("::0", port)).map_err(|_| PortStatus::InUse);
+    }
+    allowed
+        .iter()
+        .cloned()
+        .flatten()
+        .find_map(|port| TcpListener::bind(("::0", port)).ok())
+        .ok_or(PortStatus::InUse)
+}
+
+/// Returns the index of the key the client authenticated with, and the flags of the tunnel: those
+/// the client asked for and `flags`.
+fn handshake<T: Read + Write>(tcp: &mut T, keys: &[String], flags: u32) -> Option<(usize, u32)> {
+    let mut buf4 = [0u8; 4];
+    tcp.read_exact(&mut buf4).ok()?;
+    if buf4 == HEADER {
+        eprintln!("Compatible client connected.");
+        let len = tcp.read_exact(&mut buf4).map(|()| u32::from_be_bytes(buf4));
+        if let Ok(len) = len.map(|x| x as usize) {
+            if keys.iter().any(|x| x.len() == len) {
+                eprintln!("Key length matches.");
+                let mut keybuf = vec![0u8; len];
+                let tenant = tcp
+                    .read_exact(&mut keybuf)
+                    .ok()
+                    .and_then(|()| keys.iter().position(|x| x.as_bytes() == keybuf));
+                if let Some(tenant) = tenant {
+                    eprintln!("Accepted.");
+                    tcp.read_exact(&mut buf4).ok()?;
+                    let flags = u32::from_be_bytes(buf4) | flags;
+                    tcp.write_all(&HEADER).ok()?;
+                    tcp.write_all(&flags.to_be_bytes()).ok()?;
+                    return Some((tenant, flags));
+                }
+                eprintln!("Key content does not match.");
+            }
+        }
+        eprintln!("Key mismatch - forgetting client.");
+    } else if buf4[..3] == HEADER[..3] {
+        eprintln!(
+            "Client speaks protocol version {}, but {} is required - forgetting client.",
+            buf4[3], HEADER[3]
+        );
+    }
+    None
+}
+
+/// Authenticates clients connecting to `listener`, each on its own thread so a slow one can't
+/// hold up the others, and hands them to the main loop.
+fn accept_clients(
+    listener: TcpListener,
+    keys: Vec<String>,
+    flags: u32,
+    timeout: Duration,
+    clients: Sender<(TcpStream, SocketAddr, usize, u32)>,
+) {
+    // each thread holds a reference while it runs, even if it panics.
+    let running = Arc::new(());
+    for mut tcp in listener.incoming().flatten() {
+        if Arc::strong_count(&running) > MAX_AUTHENTICATING {
+            let _ = tcp.shutdown(Shutdown::Both);
+            continue;
+        }
+        let (keys, clients, running) = (keys.clone(), clients.clone(), running.clone());
+        // if no thread can be started, the connection is just dropped.
+        let _ = thread::Builder::new().spawn(move || {
+            let _running = running;
+            let Ok(addr) = tcp.peer_addr() else {
+                return;
+            };
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            let _ = tcp.set_read_timeout(Some(timeout));
+            match handshake(&mut tcp, &keys, flags) {
+                Some((tenant, flags)) => {
+                    let _ = clients.send((tcp, addr, tenant, flags));
+                }
+                None => {
+                    let _ = tcp.shutdown(Shutdown::Both);
+                }
+            }
+        });
+    }
+}
+
+/// How a peer says where it wants to go.
+#[derive(Clone, Copy)]
+enum Handshake {
+    Socks,
+    Http,
+    Tls,
+}
+
+/// What the handshakes of SOCKS5, HTTP and TLS peers need to know.
+#[derive(Clone)]
+struct Routing {
+    users: Vec<(String, String)>,
+    vhosts: Vec<(String, String)>,
+    /// The response HTTP peers asking for other hosts get.
+    fallback_status: u16,
+    timeout: Duration,
+}
+
+/// A peer's connection during its handshake, which has to be done by `deadline` in total, not just
+/// within a timeout for each read.
+struct Handshaking<'a> {
+    tcp: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Handshaking<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "handshake took too long",
+            ));
+        }
+        self.tcp.set_read_timeout(Some(left))?;
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Handshaking<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+/// Negotiates with a SOCKS5 peer, or reads the request head of an HTTP peer or the ClientHello
+/// of a TLS peer, and returns it with the destination or service it asked for. Each peer gets its
+/// own thread for this, so a slow one can't hold up the others.
+fn route(
+    mut tcp: TcpStream,
+    handshake: Handshake,
+    routing: &Routing,
+) -> Option<(TcpStream, Target)> {
+    // on some systems, sockets accepted from a nonblocking listener are nonblocking, too.
+    let _ = tcp.set_nonblocking(false);
+    let mut peer = Handshaking {
+        tcp: &tcp,
+        deadline: Instant::now() + routing.timeout,
+    };
+    let tls = match handshake {
+        Handshake::Socks => {
+            let Ok((host, port)) = socks::accept(&mut peer, &routing.users) else {
+                let _ = tcp.shutdown(Shutdown::Both);
+                return None;
+            };
+            return Some((tcp, Target::Socks(host, port)));
+        }
+        Handshake::Http => false,
+        Handshake::Tls => true,
+    };
+    let result = match tls {
+        true => vhost::read_tls_host(&mut peer),
+        false => vhost::read_http_host(&mut peer),
+    };
+    let service = result.map(|(head, host)| {
+        let name = host
+            .as_deref()
+            .and_then(|x| vhost::lookup(&routing.vhosts, x));
+        name.map(|x| (x.to_owned(), head))
+    });
+    let error = match service {
+        Ok(Some((name, head))) => {
+            let http = !tls;
+            return Some((tcp, Target::Service { name, head, http }));
+        }
+        _ if tls => vhost::tls_unrecognized_name().to_vec(),
+        Ok(None) => vhost::http_error(routing.fallback_status).into_bytes(),
+        Err(_) => vhost::http_error(400).into_bytes(),
+    };
+    let _ = tcp.write_all(&error);
+    let _ = tcp.shutdown(Shutdown::Both);
+    None
+}
+
+#[cfg(unix)]
+fn accept_stdio(keys: &[String], flags: u32) -> (Connection, u32) {
+    // stdout carries the connection, so the status line must not be printed.
+    let mut tcp = Connection::new_pipe(Pipe::stdio().unwrap(), false);
+    let Some((_, flags)) = handshake(&mut tcp, keys, flags) else {
+        panic!("client on stdio failed to authenticate.");
+    };
+    (tcp, flags)
+}
+
+#[cfg(not(unix))]
+fn accept_stdio(_keys: &[String], _flags: u32) -> (Connection, u32) {
+    panic!("stdio connections are only supported on unix.");
+}
+
+pub fn server(params: ServerParams) {
+    let keys: Vec<String> = [params.key]
+        .into_iter()
+        .chain(params.tenants.iter().map(|x| x.key))
+        .map(str::to_owned)
+        .collect();
+    // a key must name exactly one tenant, or its clients would end up with another's ports.
+    for (i, key) in keys.iter().enumerate() {
+        if keys[..i].contains(key) {
+            panic!("tenant {i} uses the same key as another tenant, keys must be distinct.");
+        }
+    }
+    let mut flags = if params.checksums { CHECKSUMS } else { 0 };
+    if params.reliable {
+        flags |= RELIABLE;
+    }
+    // pongs would come too late to keep the clients from being given up on.
+    if params.ping_interval_ms >= params.peer_timeout_ms {
+        panic!("the ping interval must be shorter than the peer timeout.");
+    }
+    for (_, service) in &params.vhosts {
+        check_name(service);
+    }
+    let io_timeout = Duration::from_millis(params.io_timeout_ms);
+    let handshake_timeout = Duration::from_millis(params.handshake_timeout_ms);
+    let (new_clients, clients) = mpsc::channel();
+    // with separate public ports, clients can keep connecting on `port` for the whole run.
+    let multi_client = (params.public_port.is_some()
+        || !params.tenants.is_empty()
+        || !params.port_ranges.is_empty())
+        && !params.stdio;
+    if multi_client {
+        let listener = TcpListener::bind(("::0", params.port)).unwrap();
+        let keys = keys.clone();
+        thread::spawn(move || accept_clients(listener, keys, flags, io_timeout, new_clients));
+    }
+    let public_port = params
+        .public_port
+        .or((!multi_client).then_some(params.port));
+    let tcpl = public_port
+        .filter(|_| params.public_tcp || !(params.stdio || multi_client))
+        .map(|port| TcpListener::bind(("::0", port)).unwrap());
+
+    let mut tunnels = Vec::new();
+    match tcpl {
+        _ if params.stdio => {
+            let (tcp, flags) = accept_stdio(&keys[..1], flags);
+            tunnels.push(Tunnel::new(tcp, "stdio".into(), 0, flags, &params));
+        }
+        Some(ref tcpl) if !multi_client => loop {
+            let Ok((mut tcp, addr)) = tcpl.accept() else {
+                continue;
+            };
+            if let Some((_, flags)) = handshake(&mut tcp, &keys[..1], flags) {
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                tunnels.push(Tunnel::new(
+                    Connection::new_tcp(tcp, true),
+                    addr.to_string(),
+                    0,
+                    flags,
+                    &params,
+                ));
+                break;
+            }
+            let _ = tcp.shutdown(Shutdown::Both);
+        },
+        _ => eprintln!("Waiting for clients on port {}.", params.port),
+    }
+
+    // each listener is tagged with the tenant its connections go to.
+    let mut listeners = Vec::new();
+    if let (true, Some(tcpl)) = (params.public_tcp, tcpl) {
+        listeners.push((Route::Tenant(0), Listener::tcp(tcpl).unwrap()));
+    }
+    if let Some(path) = params.unix_socket {
+        #[cfg(unix)]
+        listeners.push((
+            Route::Tenant(0),
+            Listener::unix(path, params.unix_socket_mode).unwrap(),
+        ));
+        #[cfg(not(unix))]
+        panic!("unix socket {path} is not supported on this platform.");
+    }
+    for (i, tenant) in params.tenants.iter().enumerate() {
+        for &port in &tenant.ports {
+            let tcpl = TcpListener::bind(("::0", port)).unwrap();
+            listeners.push((Route::Tenant(i + 1), Listener::tcp(tcpl).unwrap()));
+        }
+    }
+
+    // SOCKS5, HTTP and TLS peers are routed on their own threads before they get here.
+    let (new_routed_streams, routed_streams) = mpsc::channel();
+    let routing = Routing {
+        users: params
+            .socks_users
+            .iter()
+            .map(|&(user, password)| (user.to_owned(), password.to_owned()))
+            .collect(),
+        vhosts: params
+            .vhosts
+            .iter()
+            .map(|&(host, service)| (host.to_owned(), service.to_owned()))
+            .collect(),
+        fallback_status: params.http_fallback_status,
+        timeout: handshake_timeout,
+    };
+    let routed_listeners: Vec<_> = [
+        (params.socks_port, Handshake::Socks),
+        (params.http_port, Handshake::Http),
+        (params.tls_port, Handshake::Tls),
+    ]
+    .into_iter()
+    .filter_map(|(port, handshake)| {
+        let listener = TcpListener::bind(("::0", port?)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        Some((listener, handshake))
+    })
+    .collect();
+    let mut handshakes = 0;
+    // how many of them each address has.
+    let mut handshaking: HashMap<IpAddr, usize> = HashMap::new();
+
+    // the limits apply to each tenant on its own.
+    let mut accept_buckets: Vec<_> = (0..keys.len()).map(|_| HashMap::new()).collect();
+    let mut next_tunnel = vec![0; keys.len()];
+    let mut download_limits: Vec<_> = (0..keys.len())
+        .map(|_| {
+            (params.download_limit != 0).then(|| {
+                TokenBucket::new(params.download_limit as f64, params.download_limit as f64)
+            })
+        })
+        .collect();
+    loop {
+        let mut did_anything = false;
+
+        for (tcp, addr, tenant, flags) in clients.try_iter() {
+            let name = addr.to_string();
+            let tcp = Connection::new_tcp(tcp, true);
+            tunnels.push(Tunnel::new(tcp, name, tenant, flags, &params));
+            let count = tunnels.iter().filter(|x| x.tenant == tenant).count();
+            eprintln!();
+            eprintln!("Client {addr} connected for tenant {tenant}, {count} connected for it now.");
+        }
+
+        let tunnel_listeners = tunnels
+            .iter()
+            .enumerate()
+            .flat_map(|(i, tunnel)| tunnel.listeners.iter().map(move |x| (Route::Tunnel(i), x)));
+        let accepted: Vec<_> = listeners
+            .iter()
+            .map(|(route, x)| (*route, x))
+            .chain(tunnel_listeners)
+            .filter_map(|(route, x)| {
+                x.accept()
+                    .map(|(new, addresses)| (route, new, addresses, Target::Destination))
+            })
+            .collect();
+        // SOCKS5, HTTP and TLS peers are checked before they get a thread, so unwanted ones can't
+        // tie up threads with slow handshakes.
+        for (listener, handshake) in &routed_listeners {
+            while let Ok((tcp, peer)) = listener.accept() {
+                did_anything = true;
+                let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+                let result = match handshakes < MAX_HANDSHAKES {
+                    true => params.admit(
+                        0,
+                        Some(peer),
+                        &tunnels,
+                        &handshaking,
+                        Some(&mut accept_buckets[0]),
+                    ),
+                    false => Err("too many handshakes in progress"),
+                };
+                if let Err(reason) = result {
+                    eprintln!();
+                    eprintln!("Rejected connection from {peer}: {reason}.");
+                    // like those rejected after their handshake, HTTP peers get told why.
+                    if let Handshake::Http = handshake {
+                        let _ = (&tcp).write_all(vhost::http_error(503).as_bytes());
+                    }
+                    let _ = tcp.shutdown(Shutdown::Both);
+                    continue;
+                }
+                handshakes += 1;
+                *handshaking.entry(peer.ip()).or_default() += 1;
+                let (handshake, routing) = (*handshake, routing.clone());
+                let streams = new_routed_streams.clone();
+                thread::spawn(move || {
+                    let _ = streams.send((peer.ip(), route(tcp, handshake, &routing)));
+                });
+            }
+        }
+        // SOCKS5, HTTP and TLS connections go to the clients of `key`.
+        let routed: Vec<_> = routed_streams.try_iter().collect();
+        handshakes -= routed.len();
+        for (ip, _) in &routed {
+            let count = handshaking.get_mut(ip).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                handshaking.remove(ip);
+            }
+        }
+        let routed = routed
+            .into_iter()
+            .filter_map(|x| x.1)
+            .filter_map(|(tcp, target)| {
+                // without its addresses, it would pass for a peer on the unix socket.
+                let Ok(addresses) = tcp
+                    .peer_addr()
+                    .and_then(|peer| Ok(Addresses::new(peer, tcp.local_addr()?)))
+                else {
+                    let _ = tcp.shutdown(Shutdown::Both);
+                    return None;
+                };
+                let new = Connection::new_tcp(tcp, false);
+                Some((Route::Tenant(0), new, Some(addresses), target))
+            });
+        for (route, mut new, addresses, target) in accepted.into_iter().chain(routed) {
+            let peer = addresses.map(|x| x.peer);
+            let tenant = match route {
+                Route::Tenant(tenant) => tenant,
+                Route::Tunnel(i) => tunnels[i].tenant,
+            };
+            // routed peers were counted against the accept rate before their handshake.
+            let accept_buckets = match target {
+                Target::Destination => Some(&mut accept_buckets[tenant]),
+                Target::Socks(..) | Target::Service { .. } => None,
+            };
+            let result = params
+                .admit(tenant, peer, &tunnels, &handshaking, accept_buckets)
+                .and_then(|()| match route {
+                    Route::Tenant(tenant) => params
+                        .pick(&tunnels, tenant, &mut next_tunnel[tenant])
+                        .ok_or("no client connected"),
+                    Route::Tunnel(i) => Ok(i),
+                });
+            let i = match result {
+                Ok(i) => i,
+                Err(reason) => {
+                    eprintln!();
+                    match peer {
+                        Some(peer) => eprintln!("Rejected connection from {peer}: {reason}."),
+                        None => eprintln!("Rejected connection on unix socket: {reason}."),
+                    }
+                    match target {
+                        Target::Socks(..) => {
+                            let _ = new.write_all(&socks::reply(Err(RefuseReason::Other)));
+                        }
+                        Target::Service { http: true, .. } => {
+                            let _ = new.write_all(vhost::http_error(503).as_bytes());
+                        }
+                        Target::Service { .. } | Target::Destination => (),
+                    }
+                    let _ = new.close();
+                    continue;
+                }
+            };
+            // if this fails, the client is dropped below.
+            let _ = tunnels[i].open(new, addresses, target, &params);
+            did_anything = true;
+        }
+
+        let mut streams_left = vec![0; keys.len()];
+        for tunnel in &tunnels {
+            streams_left[tunnel.tenant] += tunnel.streams.len();
+        }
+        tunnels.retain_mut(|tunnel| {
+            let tenant = tunnel.tenant;
+            let download_limit = &mut download_limits[tenant];
+            match tunnel.step(&params, download_limit, &mut streams_left[tenant]) {
+                Ok(x) => did_anything |= x,
+                Err(e) => {
+                    eprintln!();
+                    eprintln!("Client {} dropped: {e}", tunnel.name);
+                    return false;
+                }
+            }
+            true
+        });
+        if tunnels.is_empty() && !multi_client {
+            panic!("connection dropped. exiting.");
+        }
+
+        let low_latency = tunnels
+            .iter()
+            .any(|x| x.streams.values().any(|x| x.socket.is_low_latency()));
+        if !did_anything && low_latency {
+            let mut waiting = Vec::new();
+            for tunnel in &mut tunnels {
+                waiting.push(&mut tunnel.tcp.socket);
+                // streams aren't read while their tenant's download limit is used up.
+                let limit = &mut download_limits[tunnel.tenant];
+                if limit.as_mut().is_none_or(|x| x.share(1) > 0) {
+                    waiting.extend(tunnel.streams.values_mut().map(|x| &mut x.socket));
+                }
+            }
+            wait_readable(waiting, Duration::from_millis(params.sleep_delay_ms));
+        } else if !did_anything {
+            thread::sleep(Duration::from_millis(params.sleep_delay_ms));
         }
     }
 }
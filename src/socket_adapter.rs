@@ -1,10 +1,59 @@
 use std::{
     io::{Error, Read},
     io::{ErrorKind, Write},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
-use crate::{io_sync, Connection};
+use crate::{io_sync, Connection, TokenBucket};
+
+/// How long the main loops sleep when idle while a low-latency stream is open, where sockets
+/// can't be waited on.
+const LOW_LATENCY_SLEEP: Duration = Duration::from_micros(100);
+
+/// Waits until one of `sockets` has something to read, for at most `timeout`. The main loops
+/// use this instead of the poll delay while low-latency streams are open, so these are served
+/// right away without spinning. Sockets `poll` wouldn't read from now are left out, or they
+/// would end the wait right away. A modem has no descriptor to wait on, so its presence cuts
+/// the wait short.
+#[cfg(unix)]
+pub(crate) fn wait_readable<'a>(
+    sockets: impl IntoIterator<Item = &'a mut SocketAdapter>,
+    mut timeout: Duration,
+) {
+    let mut fds = Vec::new();
+    for socket in sockets {
+        if !socket.wants_read() {
+            continue;
+        }
+        match socket.internal.raw_fd() {
+            Some(fd) => fds.push(libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            }),
+            None => timeout = timeout.min(LOW_LATENCY_SLEEP),
+        }
+    }
+    if fds.is_empty() {
+        std::thread::sleep(timeout);
+        return;
+    }
+    unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_micros().div_ceil(1000) as i32,
+        )
+    };
+}
+
+#[cfg(not(unix))]
+pub(crate) fn wait_readable<'a>(
+    _sockets: impl IntoIterator<Item = &'a mut SocketAdapter>,
+    _timeout: Duration,
+) {
+    std::thread::sleep(LOW_LATENCY_SLEEP);
+}
 
 #[derive(Clone, Copy)]
 enum Broken {
@@ -27,6 +76,12 @@ pub(crate) struct SocketAdapter {
     broken: Option<Broken>,
     accumulated_delay: u128,
     ignore_until: Option<u128>,
+    read_limit: Option<TokenBucket>,
+    /// Whether `poll` saw the end of what the peer sends.
+    read_done: bool,
+    /// Whether writing was shut down.
+    write_done: bool,
+    low_latency: bool,
 }
 
 impl SocketAdapter {
@@ -39,9 +94,38 @@ impl SocketAdapter {
             broken: None,
             accumulated_delay: 0,
             ignore_until: None,
+            read_limit: None,
+            read_done: false,
+            write_done: false,
+            low_latency: false,
         }
     }
 
+    /// Limits how many bytes per second `poll` returns, 0 for no limit.
+    pub fn set_read_limit(&mut self, bytes_per_second: u64) {
+        self.read_limit = (bytes_per_second != 0)
+            .then(|| TokenBucket::new(bytes_per_second as f64, bytes_per_second as f64));
+    }
+
+    /// Sets TCP_NODELAY and makes `write_later` send right away instead of with the next
+    /// `update`, for interactive protocols.
+    pub fn set_low_latency(&mut self) -> Result<(), Error> {
+        self.low_latency = true;
+        self.internal.set_nodelay(true)
+    }
+
+    pub fn is_low_latency(&self) -> bool {
+        self.low_latency
+    }
+
+    /// Whether `poll` would read from the connection now, rather than return right away.
+    fn wants_read(&mut self) -> bool {
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_micros();
+        !self.read_done
+            && Some(now) >= self.ignore_until
+            && self.read_limit.as_mut().is_none_or(|x| x.share(1) > 0)
+    }
+
     pub fn write_later(&mut self, buf: &[u8]) -> Result<(), Error> {
         if let Some(ref x) = self.broken {
             return Err(Error::from(*x));
@@ -68,9 +152,17 @@ impl SocketAdapter {
         };
         x.copy_from_slice(buf);
         self.to_write += buf.len();
+        if self.low_latency {
+            return self.update();
+        }
         Ok(())
     }
 
+    /// Whether everything was handed to the connection.
+    pub fn is_flushed(&self) -> bool {
+        self.to_write == 0
+    }
+
     pub fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
         self.write_later(buf)?;
         self.update()
@@ -83,13 +175,11 @@ impl SocketAdapter {
         if self.to_write == 0 {
             return Ok(());
         }
-        match {
-            self.internal.set_nonblocking(false)?;
-            let r = self
-                .internal
-                .write_all(&self.write[self.written..self.written + self.to_write]);
-            r
-        } {
+        self.internal.set_nonblocking(false)?;
+        match self
+            .internal
+            .write_all(&self.write[self.written..self.written + self.to_write])
+        {
             Ok(()) => {
                 self.written = 0;
                 self.to_write = 0;
@@ -112,13 +202,11 @@ impl SocketAdapter {
         if self.to_write == 0 {
             return Ok(());
         }
-        match {
-            self.internal.set_nonblocking(!self.internal.is_serial())?;
-            let r = self
-                .internal
-                .write(&self.write[self.written..self.written + self.to_write]);
-            r
-        } {
+        self.internal.set_nonblocking(!self.internal.is_serial())?;
+        match self
+            .internal
+            .write(&self.write[self.written..self.written + self.to_write])
+        {
             Ok(x) => {
                 self.to_write -= x;
                 self.written += x;
@@ -157,9 +245,38 @@ impl SocketAdapter {
         if Some(SystemTime::UNIX_EPOCH.elapsed().unwrap().as_micros()) < self.ignore_until {
             return Ok(None);
         }
+        let len = match self.read_limit {
+            Some(ref mut limit) => limit.share(1).min(buf.len()),
+            None => buf.len(),
+        };
+        if len == 0 {
+            return Ok(None);
+        }
         self.update()?;
+        // the end was already reported.
+        if self.read_done {
+            return Ok(None);
+        }
         self.internal.set_nonblocking(true)?;
-        io_sync(self.internal.read(buf))
+        let result = io_sync(self.internal.read(&mut buf[..len]));
+        if let (Some(limit), Ok(Some(len))) = (&mut self.read_limit, &result) {
+            limit.consume(*len as f64);
+        }
+        self.read_done = matches!(result, Ok(Some(0)));
+        result
+    }
+
+    /// Writes everything still buffered and then shuts down writing, so the peer sees the end
+    /// of the data while it can still send.
+    pub fn shutdown_write(&mut self) -> Result<(), Error> {
+        self.write_now()?;
+        self.write_done = true;
+        self.internal.shutdown_write()
+    }
+
+    /// Whether both directions are finished, after `poll` returned 0 and `shutdown_write`.
+    pub fn is_finished(&self) -> bool {
+        self.read_done && self.write_done
     }
 
     pub fn clear_delay(&mut self) -> u128 {
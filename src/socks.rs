@@ -0,0 +1,106 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::RefuseReason;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const PASSWORD: u8 = 2;
+const NO_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_string<T: Read>(tcp: &mut T, len: u8) -> io::Result<String> {
+    let mut buf = vec![0; len as usize];
+    tcp.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid("invalid string"))
+}
+
+/// Negotiates with a SOCKS5 (RFC 1928) client and returns the host and port of its CONNECT
+/// request. If `users` isn't empty, the client has to log in as one of them (RFC 1929).
+pub(crate) fn accept<T: Read + Write>(
+    tcp: &mut T,
+    users: &[(String, String)],
+) -> io::Result<(String, u16)> {
+    let mut buf2 = [0u8; 2];
+    tcp.read_exact(&mut buf2)?;
+    if buf2[0] != VERSION {
+        return Err(invalid("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0; buf2[1] as usize];
+    tcp.read_exact(&mut methods)?;
+    let method = if users.is_empty() { NO_AUTH } else { PASSWORD };
+    if !methods.contains(&method) {
+        tcp.write_all(&[VERSION, NO_METHOD])?;
+        return Err(invalid("no acceptable authentication method"));
+    }
+    tcp.write_all(&[VERSION, method])?;
+
+    if method == PASSWORD {
+        // the sub-negotiation has its own version, 1.
+        tcp.read_exact(&mut buf2)?;
+        let user = read_string(tcp, buf2[1])?;
+        tcp.read_exact(&mut buf2[..1])?;
+        let password = read_string(tcp, buf2[0])?;
+        let ok = users.iter().any(|x| x.0 == user && x.1 == password);
+        tcp.write_all(&[1, !ok as u8])?;
+        if !ok {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "wrong username or password",
+            ));
+        }
+    }
+
+    let mut head = [0u8; 4];
+    tcp.read_exact(&mut head)?;
+    if head[1] != CONNECT {
+        tcp.write_all(&reply_code(COMMAND_NOT_SUPPORTED))?;
+        return Err(invalid("only CONNECT is supported"));
+    }
+    let host = match head[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            tcp.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            tcp.read_exact(&mut buf2[..1])?;
+            read_string(tcp, buf2[0])?
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            tcp.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            tcp.write_all(&reply_code(ADDRESS_NOT_SUPPORTED))?;
+            return Err(invalid("unknown address type"));
+        }
+    };
+    tcp.read_exact(&mut buf2)?;
+    Ok((host, u16::from_be_bytes(buf2)))
+}
+
+/// The reply to a CONNECT request once the client connected the stream, or couldn't.
+pub(crate) fn reply(result: Result<(), RefuseReason>) -> [u8; 10] {
+    reply_code(match result {
+        Ok(()) => 0,
+        Err(RefuseReason::Other) => 1,
+        Err(RefuseReason::NotAllowed | RefuseReason::PermissionDenied) => 2,
+        Err(RefuseReason::NotFound | RefuseReason::TimedOut) => 4,
+        Err(RefuseReason::ConnectionRefused) => 5,
+    })
+}
+
+fn reply_code(code: u8) -> [u8; 10] {
+    // the bound address is of no use through the tunnel, so it is left empty.
+    [VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
@@ -0,0 +1,37 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+};
+
+use crate::Addresses;
+
+/// What is known about a forwarded connection. The id is the same on the client and the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub id: u64,
+    /// The public peer, `None` if it connected through a unix socket.
+    pub peer: Option<SocketAddr>,
+    /// The address on the server the peer connected to.
+    pub local: Option<SocketAddr>,
+}
+
+impl StreamInfo {
+    pub(crate) fn new(id: u64, addresses: Option<Addresses>) -> Self {
+        Self {
+            id,
+            peer: addresses.map(|x| x.peer),
+            local: addresses.map(|x| x.local),
+        }
+    }
+}
+
+impl Display for StreamInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.peer, self.local) {
+            (Some(peer), Some(local)) => {
+                write!(f, "#{} from {peer} on port {}", self.id, local.port())
+            }
+            _ => write!(f, "#{} on unix socket", self.id),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::SystemTime;
+
+/// Allows `rate` units per second on average, and bursts of up to `capacity` units.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: SystemTime::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed().unwrap_or_default().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = SystemTime::now();
+    }
+
+    pub fn take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// How much one of `ways` users may take right now, if all of them want the same.
+    pub fn share(&mut self, ways: usize) -> usize {
+        self.refill();
+        (self.tokens / ways.max(1) as f64) as usize
+    }
+
+    pub fn consume(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::{self, ErrorKind, Read};
+
+/// How much of a request is read at most to find the host it is for.
+const MAX_HEAD: usize = 8192;
+
+/// Reads the head of an HTTP request and returns what was read along with the host from its
+/// `Host:` header, without the port.
+pub(crate) fn read_http_host<T: Read>(tcp: &mut T) -> io::Result<(Vec<u8>, Option<String>)> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|x| x == b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        match tcp.read(&mut buf)? {
+            0 => return Err(ErrorKind::UnexpectedEof.into()),
+            len => head.extend_from_slice(&buf[..len]),
+        }
+    }
+    let host = String::from_utf8_lossy(&head)
+        .split("\r\n")
+        .skip(1)
+        .take_while(|x| !x.is_empty())
+        .filter_map(|x| x.split_once(':'))
+        .find(|x| x.0.trim().eq_ignore_ascii_case("host"))
+        .map(|x| strip_port(x.1.trim()).to_ascii_lowercase());
+    Ok((head, host))
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(ip) = host.strip_prefix('[') {
+        return ip.split_once(']').map_or(ip, |x| x.0);
+    }
+    host.split_once(':').map_or(host, |x| x.0)
+}
+
+/// The service `host` is mapped to. Hosts may start with `*.` to match all subdomains.
+pub(crate) fn lookup<'a>(vhosts: &'a [(String, String)], host: &str) -> Option<&'a str> {
+    vhosts
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(host))
+        .or_else(|| {
+            vhosts.iter().find(|x| {
+                x.0.strip_prefix("*.").is_some_and(|domain| {
+                    host.len() > domain.len()
+                        && host
+                            .to_ascii_lowercase()
+                            .ends_with(&domain.to_ascii_lowercase())
+                        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+                })
+            })
+        })
+        .map(|x| x.1.as_str())
+}
+
+/// The response to requests for hosts that aren't served.
+pub(crate) fn http_error(status: u16) -> String {
+    let reason = match status {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        421 => "Misdirected Request",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown Host",
+    };
+    format!("HTTP/1.1 {status} {reason}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+/// Reads the TLS ClientHello, which may span several records, and returns what was read along
+/// with the host from its server name extension.
+pub(crate) fn read_tls_host<T: Read>(tcp: &mut T) -> io::Result<(Vec<u8>, Option<String>)> {
+    let invalid = |message| io::Error::new(ErrorKind::InvalidData, message);
+    let mut head = Vec::new();
+    let mut hello = Vec::new();
+    // the handshake header holds the length of the ClientHello.
+    while hello.len() < 4
+        || hello.len() < 4 + u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize
+    {
+        let mut record = [0u8; 5];
+        tcp.read_exact(&mut record)?;
+        if record[0] != 0x16 {
+            return Err(invalid("not a TLS handshake"));
+        }
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        if head.len() + record.len() + len > 2 * MAX_HEAD {
+            return Err(invalid("ClientHello too long"));
+        }
+        let mut fragment = vec![0; len];
+        tcp.read_exact(&mut fragment)?;
+        head.extend_from_slice(&record);
+        head.extend_from_slice(&fragment);
+        hello.extend_from_slice(&fragment);
+    }
+    if hello[0] != 1 {
+        return Err(invalid("not a ClientHello"));
+    }
+    Ok((head, server_name(&hello[4..])))
+}
+
+fn server_name(hello: &[u8]) -> Option<String> {
+    // version and random, then the session id, cipher suites and compression methods.
+    let mut rest = hello.get(34..)?;
+    let mut skip = |len_bytes: usize| {
+        let len = rest
+            .get(..len_bytes)?
+            .iter()
+            .fold(0, |len, &x| len << 8 | x as usize);
+        rest = rest.get(len_bytes + len..)?;
+        Some(())
+    };
+    skip(1)?;
+    skip(2)?;
+    skip(1)?;
+    let mut extensions = rest.get(2..)?;
+    while extensions.len() >= 4 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let data = extensions.get(4..4 + len)?;
+        extensions = &extensions[4 + len..];
+        // server_name: a list of names, of which only host names (type 0) exist.
+        if kind == 0 {
+            let len = u16::from_be_bytes([*data.get(3)?, *data.get(4)?]) as usize;
+            let name = data.get(5..5 + len)?;
+            return (data[2] == 0)
+                .then(|| String::from_utf8(name.to_vec()).ok())
+                .flatten()
+                .map(|x| x.to_ascii_lowercase());
+        }
+    }
+    None
+}
+
+/// A fatal unrecognized_name alert for TLS peers asking for a host that isn't served.
+pub(crate) fn tls_unrecognized_name() -> [u8; 7] {
+    [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 112]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello as sent in a TLS record, with `host` as its only extension.
+    fn client_hello(host: &str) -> Vec<u8> {
+        let mut name = vec![0];
+        name.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        name.extend_from_slice(host.as_bytes());
+        let mut extension = vec![0, 0];
+        extension.extend_from_slice(&(name.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&name);
+        // version, random, no session id, one cipher suite, no compression.
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension);
+        let mut hello = vec![1];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn server_name_is_read() {
+        let record = client_hello("Example.com");
+        let (head, host) = read_tls_host(&mut record.as_slice()).unwrap();
+        assert_eq!(head, record);
+        assert_eq!(host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn truncated_client_hello() {
+        let record = client_hello("example.com");
+        for len in 0..record.len() {
+            assert!(read_tls_host(&mut &record[..len]).is_err());
+        }
+        // the ClientHello without its record and handshake headers.
+        let hello = &record[9..];
+        for len in 0..hello.len() {
+            assert_eq!(server_name(&hello[..len]), None);
+        }
+    }
+
+    #[test]
+    fn wildcards_match_subdomains() {
+        let vhosts: Vec<_> = [
+            ("a.test", "one"),
+            ("*.b.test", "two"),
+            ("x.b.test", "three"),
+        ]
+        .iter()
+        .map(|&(host, service)| (host.to_owned(), service.to_owned()))
+        .collect();
+        assert_eq!(lookup(&vhosts, "a.test"), Some("one"));
+        assert_eq!(lookup(&vhosts, "A.Test"), Some("one"));
+        assert_eq!(lookup(&vhosts, "x.b.test"), Some("three"));
+        assert_eq!(lookup(&vhosts, "y.b.test"), Some("two"));
+        assert_eq!(lookup(&vhosts, "Deep.Y.B.test"), Some("two"));
+        assert_eq!(lookup(&vhosts, "b.test"), None);
+        assert_eq!(lookup(&vhosts, "xb.test"), None);
+        assert_eq!(lookup(&vhosts, "sub.a.test"), None);
+    }
+}